@@ -0,0 +1,896 @@
+use crate::cpu::{AddressingMode, CpuVariant};
+use crate::opcodes::{self, OpCode};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+//.orgがないときの配置先(CPU::loadと同じ)
+const DEFAULT_ORIGIN:u16=0x0600;
+const PRG_ROM:u16=0x8000;
+
+/// アセンブルのエラー。lineは1始まりの行番号(プログラム全体に関するものは0)
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct AsmError{
+    pub line:usize,
+    pub message:String,
+}
+
+impl AsmError{
+    fn new(line:usize,message:impl Into<String>)->Self{
+        AsmError{line,message:message.into()}
+    }
+}
+
+impl fmt::Display for AsmError{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        if self.line==0{
+            write!(f,"{}",self.message)
+        }else{
+            write!(f,"line {}: {}",self.line,self.message)
+        }
+    }
+}
+
+impl Error for AsmError{}
+
+/// アセンブル結果
+#[derive(Debug,Clone)]
+pub struct Assembly{
+    pub origin:u16,//bytes[0]のアドレス
+    pub bytes:Vec<u8>,//.orgで空いたところは0で埋める
+    pub labels:HashMap<String,u16>,//ローカルラベルは"global@local"
+    pub constants:HashMap<String,u16>,//defineと"name = expr"で決めた値
+    pub line_addresses:Vec<(usize,u16)>,//(行番号,命令のアドレス)
+}
+
+impl Assembly{
+    /// マッパー0、PRG-ROM 32KBのiNESイメージにする。プログラムは$8000~$FFFFに置くこと
+    /// (ベクタは".org $FFFA"と".word"で書く)
+    pub fn to_ines(&self)->Result<Vec<u8>,AsmError>{
+        if self.origin<PRG_ROM{
+            return Err(AsmError::new(0,format!("${:04X} is outside of PRG-ROM ($8000-$FFFF)",self.origin)));
+        }
+        let mut prg_rom=vec![0;0x8000];
+        let start=(self.origin-PRG_ROM) as usize;
+        prg_rom[start..start+self.bytes.len()].copy_from_slice(&self.bytes);
+
+        let mut image=vec![0x4E,0x45,0x53,0x1A,0x02,0x01,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00];
+        image.extend(prg_rom);
+        image.extend(vec![0;0x2000]);//CHR-ROM 8KB
+        Ok(image)
+    }
+}
+
+/// ソースをアセンブルしてバイト列を返す
+///
+/// `assemble("LDA #$05\nSTA $10\nBRK")`は`[0xa9,0x05,0x85,0x10,0x00]`になる
+pub fn assemble(source:&str)->Result<Vec<u8>,AsmError>{
+    Ok(assemble_program(source)?.bytes)
+}
+
+/// ソースをアセンブルして、配置先やシンボルも含めた結果を返す
+pub fn assemble_program(source:&str)->Result<Assembly,AsmError>{
+    assemble_program_for(CpuVariant::Ricoh2A03,source)
+}
+
+/// CPUの種類を指定してアセンブルする。65C02ならBRAやSTZなども使える
+pub fn assemble_program_for(variant:CpuVariant,source:&str)->Result<Assembly,AsmError>{
+    let mut assembler=Assembler::new(variant);
+    for (i,line) in source.lines().enumerate(){
+        assembler.first_pass(i+1,line).map_err(|message|AsmError::new(i+1,message))?;
+    }
+    assembler.second_pass()
+}
+
+enum ItemKind{
+    Instruction{opcode:&'static OpCode,operand:Option<String>},
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+//1パス目で配置先と命令の長さを決めたもの
+struct Item{
+    line:usize,
+    addr:u16,
+    scope:String,
+    kind:ItemKind,
+}
+
+struct Assembler{
+    variant:CpuVariant,
+    pc:u32,//0x10000を超えたらエラーにするためu32
+    scope:String,//直前のグローバルラベル
+    symbols:HashMap<String,u16>,//ラベルと定数。式はどちらも参照できる
+    constants:HashSet<String>,//symbolsのうち定数の名前
+    items:Vec<Item>,
+}
+
+impl Assembler{
+    fn new(variant:CpuVariant)->Self{
+        Assembler{
+            variant,
+            pc:DEFAULT_ORIGIN as u32,
+            scope:String::new(),
+            symbols:HashMap::new(),
+            constants:HashSet::new(),
+            items:vec![],
+        }
+    }
+
+    fn define(&mut self,name:&str,value:u16)->Result<(),String>{
+        let name=qualify(&self.scope,name);
+        if self.symbols.insert(name.clone(),value).is_some(){
+            return Err(format!("symbol '{}' is already defined",name));
+        }
+        Ok(())
+    }
+
+    fn define_constant(&mut self,name:&str,value:u16)->Result<(),String>{
+        self.define(name,value)?;
+        self.constants.insert(qualify(&self.scope,name));
+        Ok(())
+    }
+
+    fn eval(&self,expr:&str)->Result<i64,EvalError>{
+        eval(expr,&self.symbols,&self.scope,self.pc as u16)
+    }
+
+    //1パス目で値が決まっていないといけない式(.org、定数)
+    fn eval_now(&self,expr:&str)->Result<i64,String>{
+        self.eval(expr).map_err(|e|e.to_string())
+    }
+
+    fn first_pass(&mut self,line_no:usize,line:&str)->Result<(),String>{
+        let mut rest=strip_comment(line).trim();
+
+        //ラベル(1行に複数あってもよい)
+        while let Some((name,after))=split_label(rest){
+            if !name.starts_with('@'){
+                self.scope=name.to_string();
+            }
+            if self.pc>0xFFFF{
+                return Err("address overflow".to_string());
+            }
+            self.define(name,self.pc as u16)?;
+            rest=after.trim();
+        }
+        if rest.is_empty(){
+            return Ok(());
+        }
+
+        //"name = expr"と、Easy6502の"define name expr"
+        if let Some((name,expr))=rest.split_once('='){
+            let name=name.trim();
+            if name.starts_with(is_ident_start)&&name.chars().all(|c|is_ident_char(c)||c=='@'){
+                let value=to_u16(self.eval_now(expr)?)?;
+                return self.define_constant(name,value);
+            }
+        }
+        let (head,operand)=match rest.find(char::is_whitespace){
+            Some(i)=>(&rest[..i],rest[i..].trim()),
+            None=>(rest,""),
+        };
+        if head.eq_ignore_ascii_case("define"){
+            let (name,expr)=match operand.find(char::is_whitespace){
+                Some(i)=>(&operand[..i],&operand[i..]),
+                None=>return Err("define needs a name and a value".to_string()),
+            };
+            let value=to_u16(self.eval_now(expr)?)?;
+            return self.define_constant(name,value);
+        }
+
+        let kind=match head.to_ascii_lowercase().as_str(){
+            ".org"=>{
+                self.pc=to_u16(self.eval_now(operand)?)? as u32;
+                return Ok(());
+            }
+            //変数の置き場所など、何も書き込まずにアドレスだけ進める
+            ".res"|".ds"=>{
+                let size=self.eval_now(operand)?;
+                if !(0..=0x10000).contains(&size)||self.pc+size as u32>0x10000{
+                    return Err("address overflow".to_string());
+                }
+                self.pc+=size as u32;
+                return Ok(());
+            }
+            ".byte"|".db"=>ItemKind::Bytes(split_args(operand)?),
+            ".word"|".dw"=>ItemKind::Words(split_args(operand)?),
+            directive if directive.starts_with('.')=>return Err(format!("unknown directive '{}'",head)),
+            _=>{
+                let mnemonic=head.to_ascii_uppercase();
+                let (mode,expr)=self.addressing_mode(&mnemonic,operand)?;
+                let opcode=find_opcode(self.variant,&mnemonic,mode).ok_or_else(||{
+                    if opcodes::table(self.variant).iter().flatten().any(|op|op.mnemonic==mnemonic){
+                        format!("{} does not support {:?} addressing",mnemonic,mode)
+                    }else{
+                        format!("unknown instruction '{}'",head)
+                    }
+                })?;
+                ItemKind::Instruction{opcode,operand:expr}
+            }
+        };
+
+        let len=match &kind{
+            ItemKind::Instruction{opcode,..}=>opcode.len as u32,
+            ItemKind::Bytes(args)=>args.iter().map(|arg|match parse_string(arg){
+                Some(text)=>text.len() as u32,
+                None=>1,
+            }).sum(),
+            ItemKind::Words(args)=>2*args.len() as u32,
+        };
+        if self.pc+len>0x10000{
+            return Err("address overflow".to_string());
+        }
+        self.items.push(Item{line:line_no,addr:self.pc as u16,scope:self.scope.clone(),kind});
+        self.pc+=len;
+        Ok(())
+    }
+
+    //オペランドの書き方からアドレッシングモードを決める
+    //ゼロページにできるかはこの時点でわかっている値で判断し、前方参照は絶対アドレスにする
+    fn addressing_mode(&self,mnemonic:&str,operand:&str)->Result<(AddressingMode,Option<String>),String>{
+        let supports=|mode|find_opcode(self.variant,mnemonic,mode).is_some();
+
+        if operand.is_empty(){
+            let mode=if !supports(AddressingMode::NoneAddressing)&&supports(AddressingMode::Accumulator){
+                AddressingMode::Accumulator
+            }else{
+                AddressingMode::NoneAddressing
+            };
+            return Ok((mode,None));
+        }
+        if operand.eq_ignore_ascii_case("A"){
+            return Ok((AddressingMode::Accumulator,None));
+        }
+        if let Some(expr)=operand.strip_prefix('#'){
+            return Ok((AddressingMode::Immediate,Some(expr.trim().to_string())));
+        }
+
+        if operand.starts_with('('){
+            let close=matching_paren(operand).ok_or("unbalanced parentheses")?;
+            let inner=operand[1..close].trim();
+            let after=operand[close+1..].trim();
+            if close==operand.len()-1{
+                if let Some(expr)=strip_index(inner,'X'){
+                    let mode=if supports(AddressingMode::Absolute_X_Indirect){
+                        AddressingMode::Absolute_X_Indirect
+                    }else{
+                        AddressingMode::Indirect_X
+                    };
+                    return Ok((mode,Some(expr.to_string())));
+                }
+                if supports(AddressingMode::Indirect){
+                    return Ok((AddressingMode::Indirect,Some(inner.to_string())));
+                }
+                if supports(AddressingMode::ZeroPage_Indirect){
+                    return Ok((AddressingMode::ZeroPage_Indirect,Some(inner.to_string())));
+                }
+            }else if after.len()>1&&after[1..].trim().eq_ignore_ascii_case("Y")&&after.starts_with(','){
+                return Ok((AddressingMode::Indirect_Y,Some(inner.to_string())));
+            }
+            //"(1+2)*3"のような、ただの括弧つきの式
+        }
+
+        if supports(AddressingMode::Relative){
+            return Ok((AddressingMode::Relative,Some(operand.to_string())));
+        }
+        if supports(AddressingMode::ZeroPage_Relative){
+            return Ok((AddressingMode::ZeroPage_Relative,Some(operand.to_string())));
+        }
+
+        let (expr,zero_page,absolute)=if let Some(expr)=strip_index(operand,'X'){
+            (expr,AddressingMode::ZeroPage_X,AddressingMode::Absolute_X)
+        }else if let Some(expr)=strip_index(operand,'Y'){
+            (expr,AddressingMode::ZeroPage_Y,AddressingMode::Absolute_Y)
+        }else{
+            (operand,AddressingMode::ZeroPage,AddressingMode::Absolute)
+        };
+        let fits_zero_page=matches!(self.eval(expr),Ok(value) if (0..=0xFF).contains(&value));
+        let mode=if (fits_zero_page&&supports(zero_page))||!supports(absolute){
+            zero_page
+        }else{
+            absolute
+        };
+        Ok((mode,Some(expr.to_string())))
+    }
+
+    fn second_pass(self)->Result<Assembly,AsmError>{
+        let mut image:Vec<Option<u8>>=vec![None;0x10000];
+        let mut line_addresses=vec![];
+
+        for item in &self.items{
+            let error=|message:String|AsmError::new(item.line,message);
+            let evaluate=|expr:&str|eval(expr,&self.symbols,&item.scope,item.addr).map_err(|e|error(e.to_string()));
+            let mut bytes:Vec<u8>=vec![];
+            match &item.kind{
+                ItemKind::Instruction{opcode,operand}=>{
+                    line_addresses.push((item.line,item.addr));
+                    bytes.push(opcode.code);
+                    if let Some(expr)=operand{
+                        let branch_offset=|target:i64|{
+                            let offset=target.wrapping_sub(item.addr as i64+opcode.len as i64);
+                            if !(-128..=127).contains(&offset){
+                                return Err(error(format!("branch target is out of range ({} bytes)",offset)));
+                            }
+                            Ok(offset as u8)
+                        };
+                        match opcode.mode{
+                            AddressingMode::ZeroPage_Relative=>{
+                                //"BBR0 zp,target"
+                                let args=split_args(expr).map_err(error)?;
+                                let [zero_page,target]=args.as_slice() else{
+                                    return Err(error(format!("{} needs a zero page address and a branch target",opcode.mnemonic)));
+                                };
+                                let zero_page=evaluate(zero_page)?;
+                                if !(0..=0xFF).contains(&zero_page){
+                                    return Err(error(format!("${:X} is not a zero page address",zero_page)));
+                                }
+                                bytes.push(zero_page as u8);
+                                bytes.push(branch_offset(evaluate(target)?)?);
+                            }
+                            AddressingMode::Relative=>bytes.push(branch_offset(evaluate(expr)?)?),
+                            AddressingMode::Immediate=>bytes.push(to_u8(evaluate(expr)?).map_err(error)?),
+                            _ if opcode.len==2=>{
+                                let value=evaluate(expr)?;
+                                if !(0..=0xFF).contains(&value){
+                                    return Err(error(format!("${:X} is not a zero page address",value)));
+                                }
+                                bytes.push(value as u8);
+                            }
+                            _=>bytes.extend(to_u16(evaluate(expr)?).map_err(error)?.to_le_bytes()),
+                        }
+                    }
+                }
+                ItemKind::Bytes(args)=>{
+                    for arg in args{
+                        match parse_string(arg){
+                            Some(text)=>bytes.extend(text.bytes()),
+                            None=>bytes.push(to_u8(evaluate(arg)?).map_err(error)?),
+                        }
+                    }
+                }
+                ItemKind::Words(args)=>{
+                    for arg in args{
+                        bytes.extend(to_u16(evaluate(arg)?).map_err(error)?.to_le_bytes());
+                    }
+                }
+            }
+
+            for (i,byte) in bytes.into_iter().enumerate(){
+                let addr=item.addr as usize+i;
+                if image[addr].is_some(){
+                    return Err(error(format!("${:04X} is already used by another .org block",addr)));
+                }
+                image[addr]=Some(byte);
+            }
+        }
+
+        let start=image.iter().position(|byte|byte.is_some());
+        let end=image.iter().rposition(|byte|byte.is_some());
+        let (origin,bytes)=match (start,end){
+            (Some(start),Some(end))=>(start as u16,image[start..=end].iter().map(|byte|byte.unwrap_or(0)).collect()),
+            _=>(DEFAULT_ORIGIN,vec![]),
+        };
+        let (constants,labels)=self.symbols.into_iter().partition(|(name,_)|self.constants.contains(name));
+        Ok(Assembly{origin,bytes,labels,constants,line_addresses})
+    }
+}
+
+//ローカルラベル(@name)は直前のグローバルラベルの名前をつけて区別する
+fn qualify(scope:&str,name:&str)->String{
+    if name.starts_with('@'){
+        format!("{}{}",scope,name)
+    }else{
+        name.to_string()
+    }
+}
+
+fn find_opcode(variant:CpuVariant,mnemonic:&str,mode:AddressingMode)->Option<&'static OpCode>{
+    //同じ書き方ができるときは公式の命令を優先する(NOP,SBC #など)
+    opcodes::table(variant)
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|op|op.mnemonic==mnemonic&&op.mode==mode)
+        .min_by_key(|op|op.unofficial)
+}
+
+fn to_u8(value:i64)->Result<u8,String>{
+    if (-128..=0xFF).contains(&value){
+        Ok(value as u8)
+    }else{
+        Err(format!("${:X} does not fit in a byte",value))
+    }
+}
+
+fn to_u16(value:i64)->Result<u16,String>{
+    if (0..=0xFFFF).contains(&value){
+        Ok(value as u16)
+    }else{
+        Err(format!("${:X} does not fit in a word",value))
+    }
+}
+
+fn is_ident_start(c:char)->bool{
+    c.is_ascii_alphabetic()||c=='_'||c=='@'
+}
+
+fn is_ident_char(c:char)->bool{
+    c.is_ascii_alphanumeric()||c=='_'
+}
+
+//"name:"で始まっていれば(name,残り)
+fn split_label(text:&str)->Option<(&str,&str)>{
+    let first=text.chars().next()?;
+    if !is_ident_start(first){
+        return None;
+    }
+    let end=text[1..].find(|c|!is_ident_char(c)).map(|i|i+1).unwrap_or(text.len());
+    text[end..].strip_prefix(':').map(|rest|(&text[..end],rest))
+}
+
+//";"以降を消す。文字列と文字リテラルの中の";"は残す
+fn strip_comment(line:&str)->&str{
+    let mut quote=None;
+    for (i,c) in line.char_indices(){
+        match quote{
+            Some(q) if c==q=>quote=None,
+            Some(_)=>{}
+            None if c=='"'||c=='\''=>quote=Some(c),
+            None if c==';'=>return &line[..i],
+            None=>{}
+        }
+    }
+    line
+}
+
+//カンマ区切りの引数。文字列の中のカンマでは区切らない
+fn split_args(text:&str)->Result<Vec<String>,String>{
+    let mut args=vec![];
+    let mut current=String::new();
+    let mut quote=None;
+    for c in text.chars(){
+        match quote{
+            Some(q) if c==q=>quote=None,
+            Some(_)=>{}
+            None if c=='"'||c=='\''=>quote=Some(c),
+            None if c==','=>{
+                args.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            None=>{}
+        }
+        current.push(c);
+    }
+    args.push(current.trim().to_string());
+    if args.iter().any(|arg|arg.is_empty()){
+        return Err("missing argument".to_string());
+    }
+    Ok(args)
+}
+
+fn parse_string(arg:&str)->Option<&str>{
+    arg.strip_prefix('"')?.strip_suffix('"')
+}
+
+//"expr,X"の"expr"
+fn strip_index(operand:&str,register:char)->Option<&str>{
+    let (expr,index)=operand.rsplit_once(',')?;
+    if index.trim().len()==1&&index.trim().starts_with(|c:char|c.to_ascii_uppercase()==register){
+        Some(expr.trim())
+    }else{
+        None
+    }
+}
+
+//先頭の"("に対応する")"の位置
+fn matching_paren(text:&str)->Option<usize>{
+    let mut depth=0;
+    for (i,c) in text.char_indices(){
+        match c{
+            '('=>depth+=1,
+            ')'=>{
+                depth-=1;
+                if depth==0{
+                    return Some(i);
+                }
+            }
+            _=>{}
+        }
+    }
+    None
+}
+
+#[derive(Debug)]
+enum EvalError{
+    Undefined(String),//1パス目では前方参照の可能性がある
+    Syntax(String),
+}
+
+impl fmt::Display for EvalError{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        match self{
+            EvalError::Undefined(name)=>write!(f,"undefined symbol '{}'",name),
+            EvalError::Syntax(message)=>write!(f,"{}",message),
+        }
+    }
+}
+
+/// 式を評価する。"*"は現在のアドレス、"<"と">"は下位・上位バイト
+fn eval(expr:&str,symbols:&HashMap<String,u16>,scope:&str,pc:u16)->Result<i64,EvalError>{
+    let mut parser=ExprParser{chars:expr.chars().collect(),pos:0,symbols,scope,pc};
+    let value=parser.binary(0)?;
+    parser.skip_space();
+    if parser.pos<parser.chars.len(){
+        return Err(EvalError::Syntax(format!("unexpected '{}' in expression",parser.chars[parser.pos])));
+    }
+    Ok(value)
+}
+
+//優先順位の低い順
+const BINARY_OPERATORS:&[&[&str]]=&[&["|"],&["^"],&["&"],&["<<",">>"],&["+","-"],&["*","/","%"]];
+
+struct ExprParser<'a>{
+    chars:Vec<char>,
+    pos:usize,
+    symbols:&'a HashMap<String,u16>,
+    scope:&'a str,
+    pc:u16,
+}
+
+impl ExprParser<'_>{
+    fn skip_space(&mut self){
+        while self.pos<self.chars.len()&&self.chars[self.pos].is_whitespace(){
+            self.pos+=1;
+        }
+    }
+
+    fn peek(&self)->Option<char>{
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self,token:&str)->bool{
+        self.skip_space();
+        let len=token.chars().count();
+        if self.chars.len()>=self.pos+len&&self.chars[self.pos..self.pos+len].iter().copied().eq(token.chars()){
+            self.pos+=len;
+            true
+        }else{
+            false
+        }
+    }
+
+    fn binary(&mut self,level:usize)->Result<i64,EvalError>{
+        if level==BINARY_OPERATORS.len(){
+            return self.unary();
+        }
+        let mut value=self.binary(level+1)?;
+        'outer: loop{
+            for op in BINARY_OPERATORS[level]{
+                if self.eat(op){
+                    let rhs=self.binary(level+1)?;
+                    value=match *op{
+                        "|"=>value|rhs,
+                        "^"=>value^rhs,
+                        "&"=>value&rhs,
+                        "<<"=>value.checked_shl(rhs as u32).unwrap_or(0),
+                        ">>"=>value.checked_shr(rhs as u32).unwrap_or(0),
+                        "+"=>value.wrapping_add(rhs),
+                        "-"=>value.wrapping_sub(rhs),
+                        "*"=>value.wrapping_mul(rhs),
+                        _=>{
+                            if rhs==0{
+                                return Err(EvalError::Syntax("division by zero".to_string()));
+                            }
+                            let result=if *op=="/"{value.checked_div(rhs)}else{value.checked_rem(rhs)};
+                            result.ok_or_else(||EvalError::Syntax("division overflow".to_string()))?
+                        }
+                    };
+                    continue 'outer;
+                }
+            }
+            return Ok(value);
+        }
+    }
+
+    fn unary(&mut self)->Result<i64,EvalError>{
+        if self.eat("-"){
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        if self.eat("~"){
+            return Ok(!self.unary()?);
+        }
+        if self.eat("<"){
+            return Ok(self.unary()?&0xFF);
+        }
+        if self.eat(">"){
+            return Ok((self.unary()?>>8)&0xFF);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self)->Result<i64,EvalError>{
+        self.skip_space();
+        let syntax=|message:&str|EvalError::Syntax(message.to_string());
+        match self.peek(){
+            Some('(')=>{
+                self.pos+=1;
+                let value=self.binary(0)?;
+                if !self.eat(")"){
+                    return Err(syntax("missing ')'"));
+                }
+                Ok(value)
+            }
+            Some('*')=>{
+                self.pos+=1;
+                Ok(self.pc as i64)
+            }
+            Some('$')=>{
+                self.pos+=1;
+                self.number(16)
+            }
+            Some('%')=>{
+                self.pos+=1;
+                self.number(2)
+            }
+            Some('\'')=>{
+                match (self.chars.get(self.pos+1),self.chars.get(self.pos+2)){
+                    (Some(c),Some('\''))=>{
+                        self.pos+=3;
+                        Ok(*c as i64)
+                    }
+                    _=>Err(syntax("invalid character literal")),
+                }
+            }
+            Some(c) if c.is_ascii_digit()=>self.number(10),
+            Some(c) if is_ident_start(c)=>{
+                let start=self.pos;
+                self.pos+=1;
+                while self.peek().is_some_and(is_ident_char){
+                    self.pos+=1;
+                }
+                let name:String=self.chars[start..self.pos].iter().collect();
+                let name=qualify(self.scope,&name);
+                match self.symbols.get(&name){
+                    Some(value)=>Ok(*value as i64),
+                    None=>Err(EvalError::Undefined(name)),
+                }
+            }
+            Some(c)=>Err(EvalError::Syntax(format!("unexpected '{}' in expression",c))),
+            None=>Err(syntax("missing operand")),
+        }
+    }
+
+    fn number(&mut self,radix:u32)->Result<i64,EvalError>{
+        let start=self.pos;
+        while self.peek().is_some_and(|c|c.is_digit(radix)){
+            self.pos+=1;
+        }
+        let digits:String=self.chars[start..self.pos].iter().collect();
+        i64::from_str_radix(&digits,radix)
+            .ok()
+            .filter(|value|*value<=0xFFFF_FFFF)
+            .ok_or_else(||EvalError::Syntax(format!("invalid number '{}'",digits)))
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::cartridge::Rom;
+
+    #[test]
+    fn test_assemble_simple_program(){
+        assert_eq!(assemble("LDA #$05\nSTA $10\nBRK").unwrap(),vec![0xa9,0x05,0x85,0x10,0x00]);
+    }
+
+    #[test]
+    fn test_all_addressing_modes(){
+        let source="
+            lda #10
+            lda $10
+            lda $10,x
+            ldx $10,y
+            lda $1234
+            lda $1234,X
+            lda $1234,Y
+            lda $10,y       ; LDAにゼロページ,Yはないので絶対アドレス,Y
+            jmp ($fffc)
+            lda ($10,x)
+            lda ($10),y
+            asl a
+            lsr
+            nop
+        ";
+        assert_eq!(assemble(source).unwrap(),vec![
+            0xa9,0x0a,
+            0xa5,0x10,
+            0xb5,0x10,
+            0xb6,0x10,
+            0xad,0x34,0x12,
+            0xbd,0x34,0x12,
+            0xb9,0x34,0x12,
+            0xb9,0x10,0x00,
+            0x6c,0xfc,0xff,
+            0xa1,0x10,
+            0xb1,0x10,
+            0x0a,
+            0x4a,
+            0xea,
+        ]);
+    }
+
+    #[test]
+    fn test_labels_and_branches(){
+        let source="
+            ldx #3
+        loop:
+            dex
+            bne loop
+            jsr done
+            beq done
+            nop
+        done:
+            rts
+        ";
+        let program=assemble_program(source).unwrap();
+        assert_eq!(program.bytes,vec![0xa2,0x03,0xca,0xd0,0xfd,0x20,0x0b,0x06,0xf0,0x01,0xea,0x60]);
+        assert_eq!(program.labels["loop"],0x0602);
+        assert_eq!(program.labels["done"],0x060b);
+        assert_eq!(program.line_addresses[0],(2,0x0600));
+    }
+
+    #[test]
+    fn test_reserve_and_constants(){
+        //.resは何も書かずにアドレスだけ進める
+        let program=assemble_program(".org $02\ncounter: .res 2\nnext: .res 1\n.org $0600\ndefine step 2\nsize = 3\n inc counter").unwrap();
+        assert_eq!(program.origin,0x0600);
+        assert_eq!(program.bytes,vec![0xe6,0x02]);
+        assert_eq!(program.labels["next"],0x0004);
+        assert_eq!(program.constants["step"],2);
+        assert_eq!(program.constants["size"],3);
+        assert!(!program.labels.contains_key("step"));
+        assert!(assemble(".org $ffff\nbuffer: .res 2").is_err());
+    }
+
+    #[test]
+    fn test_forward_reference_uses_absolute(){
+        //後で定義されるラベルはゼロページに収まっても絶対アドレスで書く
+        let program=assemble_program("lda var\n.org $0010\nvar: .byte 1").unwrap();
+        assert_eq!(program.origin,0x0010);
+        assert_eq!(program.bytes[..3],[0x01,0x00,0x00]);
+        assert_eq!(program.bytes[0x0600-0x10..],[0xad,0x10,0x00]);
+    }
+
+    #[test]
+    fn test_expressions_and_constants(){
+        let source="
+            define screen $0200
+            size=4*8
+            lda #<screen+size
+            ldx #>(screen+size)
+            ldy #%1010|1
+            lda #'A'
+            sta screen+size-1,x
+            .word *, screen
+            .byte \"hi;\", -1, size/3
+        ";
+        assert_eq!(assemble(source).unwrap(),vec![
+            0xa9,0x20,
+            0xa2,0x02,
+            0xa0,0x0b,
+            0xa9,0x41,
+            0x9d,0x1f,0x02,
+            0x0b,0x06,0x00,0x02,
+            0x68,0x69,0x3b,0xff,0x0a,
+        ]);
+    }
+
+    #[test]
+    fn test_local_labels(){
+        let source="
+        first:
+            ldx #2
+        @loop:
+            dex
+            bne @loop
+        second:
+            ldy #2
+        @loop:
+            dey
+            bne @loop
+            jmp first
+        ";
+        let program=assemble_program(source).unwrap();
+        assert_eq!(program.labels["first@loop"],0x0602);
+        assert_eq!(program.labels["second@loop"],0x0607);
+        assert_eq!(program.bytes[3..5],[0xd0,0xfd]);
+        assert_eq!(program.bytes[8..10],[0xd0,0xfd]);
+    }
+
+    #[test]
+    fn test_errors(){
+        assert_eq!(assemble("nop\nfoo #1").unwrap_err(),AsmError::new(2,"unknown instruction 'foo'"));
+        assert_eq!(assemble("jmp missing").unwrap_err().to_string(),"line 1: undefined symbol 'missing'");
+        assert!(assemble("sta #1").unwrap_err().message.contains("does not support"));
+        assert!(assemble("x: nop\nx: nop").is_err());
+        assert!(assemble("lda #$100").is_err());
+        assert!(assemble(".org $0000\nbne far\n.org $0100\nfar: nop").unwrap_err().message.contains("out of range"));
+        //i64の端でもパニックしない
+        assert!(assemble(".byte -(1<<63)").is_err());
+        assert!(assemble(".byte (1<<63)/-1").unwrap_err().message.contains("division overflow"));
+        assert!(assemble(".byte (1<<63)%-1").unwrap_err().message.contains("division overflow"));
+    }
+
+    #[test]
+    fn test_65c02_instructions(){
+        let source="
+        start:
+            stz $10
+            phx
+            lda ($12)
+            bbr0 $12,start
+            jmp ($1234,x)
+            bra start
+        ";
+        assert_eq!(assemble_program_for(CpuVariant::Wdc65C02,source).unwrap().bytes,vec![
+            0x64,0x10,
+            0xda,
+            0xb2,0x12,
+            0x0f,0x12,0xf8,
+            0x7c,0x34,0x12,
+            0x80,0xf3,
+        ]);
+        assert_eq!(assemble("bra start\nstart: nop").unwrap_err(),AsmError::new(1,"unknown instruction 'bra'"));
+    }
+
+    #[test]
+    fn test_ines_image(){
+        let source="
+            .org $8000
+        reset:
+            lda #1
+            jmp reset
+            .org $fffa
+            .word reset, reset, reset
+        ";
+        let program=assemble_program(source).unwrap();
+        let rom=Rom::new(&program.to_ines().unwrap()).unwrap();
+        assert_eq!(rom.prg_rom.len(),0x8000);
+        assert_eq!(rom.prg_rom[..5],[0xa9,0x01,0x4c,0x00,0x80]);
+        assert_eq!(rom.prg_rom[0x7FFC..],[0x00,0x80,0x00,0x80]);
+        assert!(assemble_program("nop").unwrap().to_ines().is_err());
+    }
+
+    //Easy6502で公開されているsnakeゲームの機械語
+    const SNAKE_CODE:&[u8]=&[
+        0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02, 0x85,
+        0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9, 0x0f, 0x85,
+        0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85, 0x00, 0xa5, 0xfe,
+        0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xc3,
+        0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c, 0x38, 0x06, 0xa5, 0xff, 0xc9,
+        0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0, 0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60,
+        0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85, 0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0,
+        0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01, 0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02,
+        0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05, 0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06,
+        0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00, 0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07,
+        0xe6, 0x03, 0xe6, 0x03, 0x20, 0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06,
+        0xb5, 0x11, 0xc5, 0x11, 0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c,
+        0x35, 0x07, 0x60, 0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02,
+        0x4a, 0xb0, 0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9,
+        0x20, 0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
+        0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10, 0xb0,
+        0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5, 0x10, 0x29,
+        0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe, 0x91, 0x00, 0x60,
+        0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10, 0x60, 0xa2, 0x00, 0xea,
+        0xea, 0xca, 0xd0, 0xfb, 0x60
+    ];
+
+    #[test]
+    fn test_snake_source(){
+        let program=assemble_program(include_str!("snake.asm")).unwrap();
+        assert_eq!(program.bytes,SNAKE_CODE);
+        assert_eq!(program.labels["gameOver"],0x0735);
+    }
+}
@@ -0,0 +1,91 @@
+// CPUの実行速度を測る
+//
+// ロード、ストア、RMW、分岐を混ぜた終わらないループを決めたサイクル数だけ回し、
+// エミュレートしたクロック(MHz)に換算する。NESのCPUは1.789773MHzなので、それより速ければ実時間で動く
+use crate::asm;
+use crate::cpu::{CpuError, CPU};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// NTSCのNES(2A03)のクロック
+pub const NES_CPU_MHZ:f64=1.789773;
+
+const LOOP_SOURCE:&str="
+        ldx #0
+        ldy #0
+    loop:
+        lda $0200,x
+        clc
+        adc #1
+        sta $0200,x
+        inc $10
+        lsr $11
+        dex
+        bne loop
+        iny
+        jsr sub
+        jmp loop
+    sub:
+        pha
+        pla
+        rts
+";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct BenchResult{
+    pub instructions:u64,
+    pub cycles:usize,
+    pub elapsed:Duration,
+}
+
+impl BenchResult{
+    /// エミュレートできたクロック
+    pub fn mhz(&self)->f64{
+        self.cycles as f64/self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)/1_000_000.0
+    }
+}
+
+impl fmt::Display for BenchResult{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        let mhz=self.mhz();
+        write!(
+            f,
+            "{} instructions, {} cycles in {:.3}s: {:.2} MHz ({:.1}x NES speed)",
+            self.instructions,
+            self.cycles,
+            self.elapsed.as_secs_f64(),
+            mhz,
+            mhz/NES_CPU_MHZ
+        )
+    }
+}
+
+/// ループをcyclesサイクル以上実行するまでの時間を測る
+pub fn run(cycles:usize)->Result<BenchResult,CpuError>{
+    let program=asm::assemble(LOOP_SOURCE).expect("benchmark loop assembles");
+    let mut cpu=CPU::new();
+    cpu.load(program)?;
+    cpu.power_on();
+
+    let mut instructions=0;
+    let start=Instant::now();
+    while cpu.cycles<cycles{
+        cpu.step()?;
+        instructions+=1;
+    }
+    Ok(BenchResult{instructions,cycles:cpu.cycles,elapsed:start.elapsed()})
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn test_bench_runs_requested_cycles(){
+        let result=run(10_000).unwrap();
+        assert!(result.cycles>=10_000);
+        assert!(result.cycles<10_000+7);//最後の1命令分だけはみ出す
+        assert!(result.instructions>0);
+        assert!(result.to_string().contains(" MHz ("));
+    }
+}
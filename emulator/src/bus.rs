@@ -0,0 +1,313 @@
+use crate::cartridge::Rom;
+use crate::cpu::Mem;
+use std::fmt;
+
+//  CPU Memory Map
+//  _______________ $10000  _______________
+// | PRG-ROM       |       |               |
+// |               |       |               |
+// |_______________| $8000 | Cartridge     |
+// | SRAM          |       |               |
+// |_______________| $6000 |               |
+// | Expansion ROM |       |               |
+// |_______________| $4020 |_______________|
+// | I/O Registers |       |               |
+// |_______________| $4000 |               |
+// | Mirrors       |       | I/O Registers |
+// | $2000-$2007   |       |               |
+// |_______________| $2008 |               |
+// | I/O Registers |       |               |
+// |_______________| $2000 |_______________|
+// | Mirrors       |       |               |
+// | $0000-$07FF   |       |               |
+// |_______________| $0800 |               |
+// | RAM           |       | RAM           |
+// |_______________| $0000 |_______________|
+
+const RAM:u16=0x0000;
+const RAM_MIRRORS_END:u16=0x1FFF;
+const PPU_REGISTERS:u16=0x2000;
+const PPU_REGISTERS_MIRRORS_END:u16=0x3FFF;
+const APU_IO_REGISTERS:u16=0x4000;
+const APU_IO_REGISTERS_END:u16=0x4017;
+const PRG_RAM:u16=0x6000;
+const PRG_RAM_END:u16=0x7FFF;
+const PRG_ROM:u16=0x8000;
+
+/// 電源を入れた直後のRAMの中身。実機では不定で、0を前提にしたゲームが動かないこともある
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RamPattern{
+    Zeros,
+    Ones,//すべて$FF
+    Random(u64),//シードを固定した擬似乱数。同じシードなら毎回同じ中身になる
+}
+
+impl RamPattern{
+    pub fn fill(&self,ram:&mut [u8]){
+        match self{
+            RamPattern::Zeros=>ram.fill(0x00),
+            RamPattern::Ones=>ram.fill(0xFF),
+            RamPattern::Random(seed)=>{
+                //xorshift64*。0は不動点なので避ける
+                let mut state=if *seed==0{0x9E37_79B9_7F4A_7C15}else{*seed};
+                for byte in ram.iter_mut(){
+                    state^=state>>12;
+                    state^=state<<25;
+                    state^=state>>27;
+                    *byte=(state.wrapping_mul(0x2545_F491_4F6C_DD1D)>>56) as u8;
+                }
+            }
+        }
+    }
+}
+
+pub struct Bus{
+    cpu_vram:[u8;2048],
+    //PPU,APUはまだないので、書き込まれた値をそのまま保持するだけ
+    ppu_registers:[u8;8],
+    apu_io_registers:[u8;0x18],
+    prg_ram:[u8;0x2000],
+    rom:Rom,
+}
+
+impl Bus{
+    pub fn new(rom:Rom)->Self{
+        Bus{
+            cpu_vram:[0;2048],
+            ppu_registers:[0;8],
+            apu_io_registers:[0;0x18],
+            prg_ram:[0;0x2000],
+            rom,
+        }
+    }
+
+    fn read_prg_rom(&self,mut addr:u16)->u8{
+        addr-=PRG_ROM;
+        if self.rom.prg_rom.len()==0x4000&&addr>=0x4000{
+            //16KBのROMは0xC000~にもミラーされる
+            addr%=0x4000;
+        }
+        self.rom.prg_rom[addr as usize]
+    }
+}
+
+impl Mem for Bus{
+    fn mem_read(&mut self,addr:u16)->u8{
+        match addr{
+            RAM..=RAM_MIRRORS_END=>{
+                let mirror_down_addr=addr&0b00000111_11111111;
+                self.cpu_vram[mirror_down_addr as usize]
+            }
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END=>{
+                let mirror_down_addr=addr&0b00100000_00000111;
+                self.ppu_registers[(mirror_down_addr-PPU_REGISTERS) as usize]
+            }
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END=>{
+                self.apu_io_registers[(addr-APU_IO_REGISTERS) as usize]
+            }
+            PRG_RAM..=PRG_RAM_END=>self.prg_ram[(addr-PRG_RAM) as usize],
+            PRG_ROM..=0xFFFF=>self.read_prg_rom(addr),
+            _=>{
+                //$4018~$5FFFはマッパーがないと何もつながっていない
+                0
+            }
+        }
+    }
+
+    fn mem_write(&mut self,addr:u16,data:u8){
+        match addr{
+            RAM..=RAM_MIRRORS_END=>{
+                let mirror_down_addr=addr&0b00000111_11111111;
+                self.cpu_vram[mirror_down_addr as usize]=data;
+            }
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END=>{
+                let mirror_down_addr=addr&0b00100000_00000111;
+                self.ppu_registers[(mirror_down_addr-PPU_REGISTERS) as usize]=data;
+            }
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END=>{
+                self.apu_io_registers[(addr-APU_IO_REGISTERS) as usize]=data;
+            }
+            PRG_RAM..=PRG_RAM_END=>self.prg_ram[(addr-PRG_RAM) as usize]=data,
+            _=>{
+                //ROMへの書き込みはマッパーがないので無視する
+            }
+        }
+    }
+
+    //PRG-RAMはバッテリーでバックアップされていることがあるのでそのまま
+    fn fill_ram(&mut self,pattern:RamPattern){
+        pattern.fill(&mut self.cpu_vram);
+    }
+}
+
+/// 64KBすべてがRAMのメモリ空間。テストやEasy6502のプログラム用
+pub struct FlatMemory{
+    memory:[u8;0x10000],
+}
+
+impl FlatMemory{
+    pub fn new()->Self{
+        FlatMemory{
+            memory:[0;0x10000],
+        }
+    }
+}
+
+impl Default for FlatMemory{
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+impl Mem for FlatMemory{
+    fn mem_read(&mut self,addr:u16)->u8{
+        self.memory[addr as usize]
+    }
+
+    fn mem_write(&mut self,addr:u16,data:u8){
+        self.memory[addr as usize]=data;
+    }
+
+    fn fill_ram(&mut self,pattern:RamPattern){
+        pattern.fill(&mut self.memory);
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BusAccess{
+    Read,
+    Write,
+}
+
+/// 1サイクルのバスの動き
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct BusCycle{
+    pub addr:u16,
+    pub value:u8,
+    pub access:BusAccess,
+}
+
+impl fmt::Display for BusCycle{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        let access=match self.access{
+            BusAccess::Read=>"read",
+            BusAccess::Write=>"write",
+        };
+        write!(f,"{} ${:04X}=${:02X}",access,self.addr,self.value)
+    }
+}
+
+/// すべてのアクセスを記録する64KBのRAM。CPUが1サイクルごとに何を読み書きしたかを確かめるのに使う
+pub struct RecordingBus{
+    memory:FlatMemory,
+    pub accesses:Vec<BusCycle>,
+}
+
+impl RecordingBus{
+    pub fn new()->Self{
+        RecordingBus{
+            memory:FlatMemory::new(),
+            accesses:vec![],
+        }
+    }
+
+    /// 記録せずに書く
+    pub fn poke(&mut self,addr:u16,data:u8){
+        self.memory.mem_write(addr,data);
+    }
+}
+
+impl Default for RecordingBus{
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+impl Mem for RecordingBus{
+    fn mem_read(&mut self,addr:u16)->u8{
+        let value=self.memory.mem_read(addr);
+        self.accesses.push(BusCycle{addr,value,access:BusAccess::Read});
+        value
+    }
+
+    fn mem_write(&mut self,addr:u16,data:u8){
+        self.memory.mem_write(addr,data);
+        self.accesses.push(BusCycle{addr,value:data,access:BusAccess::Write});
+    }
+
+    //記録せずに読む
+    fn peek(&mut self,addr:u16)->u8{
+        self.memory.mem_read(addr)
+    }
+
+    fn fill_ram(&mut self,pattern:RamPattern){
+        self.memory.fill_ram(pattern);
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_ram_mirroring(){
+        let mut bus=Bus::new(test_rom(vec![0;0x4000]));
+        bus.mem_write(0x0001,0x55);
+        assert_eq!(bus.mem_read(0x0801),0x55);
+        assert_eq!(bus.mem_read(0x1801),0x55);
+        bus.mem_write(0x1FFF,0x66);
+        assert_eq!(bus.mem_read(0x07FF),0x66);
+    }
+
+    #[test]
+    fn test_ppu_registers_mirroring(){
+        let mut bus=Bus::new(test_rom(vec![0;0x4000]));
+        bus.mem_write(0x3FFF,0x77);
+        assert_eq!(bus.mem_read(0x2007),0x77);
+        assert_eq!(bus.mem_read(0x200F),0x77);
+    }
+
+    #[test]
+    fn test_prg_rom_16kb_is_mirrored(){
+        let mut prg_rom=vec![0;0x4000];
+        prg_rom[0x3FFC]=0x00;
+        prg_rom[0x3FFD]=0x80;
+        let mut bus=Bus::new(test_rom(prg_rom));
+        assert_eq!(bus.mem_read_u16(0xBFFC),0x8000);
+        assert_eq!(bus.mem_read_u16(0xFFFC),0x8000);
+        bus.mem_write(0xFFFC,0x12);
+        assert_eq!(bus.mem_read(0xFFFC),0x00);
+    }
+
+    #[test]
+    fn test_ram_pattern(){
+        let mut a=[0;16];
+        let mut b=[0;16];
+        RamPattern::Random(1).fill(&mut a);
+        RamPattern::Random(1).fill(&mut b);
+        assert_eq!(a,b);
+        RamPattern::Random(2).fill(&mut b);
+        assert_ne!(a,b);
+        RamPattern::Ones.fill(&mut a);
+        assert_eq!(a,[0xFF;16]);
+    }
+
+    #[test]
+    fn test_fill_ram_leaves_rom_and_prg_ram(){
+        let mut bus=Bus::new(test_rom(vec![0x42;0x4000]));
+        bus.mem_write(0x6000,0x12);
+        bus.fill_ram(RamPattern::Ones);
+        assert_eq!(bus.mem_read(0x07FF),0xFF);
+        assert_eq!(bus.mem_read(0x6000),0x12);
+        assert_eq!(bus.mem_read(0x8000),0x42);
+    }
+
+    #[test]
+    fn test_flat_memory_last_byte(){
+        let mut memory=FlatMemory::new();
+        memory.mem_write(0xFFFF,0x42);
+        assert_eq!(memory.mem_read(0xFFFF),0x42);
+        assert_eq!(memory.mem_read_u16(0xFFFF),0x0042);//上位バイトは0x0000から読む
+    }
+}
@@ -0,0 +1,334 @@
+// JSRと割り込みで積んだ戻り先を、スタックとは別に覚えておく(シャドウコールスタック)
+//
+// CPU::call_stackをSomeにすると、JSR、NMI/IRQ/BRKで入ったときに積み、RTS、RTIで下ろす。
+// 戻り先をスタックに置いたときのSPも覚えておき、PLAやTXSで戻り先を捨てたり、
+// PHA,PHA,RTSのジャンプテーブルのように対応する呼び出しのない戻りをしたりしたらMismatchに残す
+use std::collections::VecDeque;
+use std::fmt;
+
+//Mismatchを覚えておく数。ジャンプテーブルを毎フレーム使うゲームでも増え続けないようにする
+const MAX_MISMATCHES:usize=64;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FrameKind{
+    Jsr,
+    Nmi,
+    Irq,
+    Brk,
+}
+
+impl FrameKind{
+    fn is_interrupt(self)->bool{
+        self!=FrameKind::Jsr
+    }
+}
+
+impl fmt::Display for FrameKind{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        let name=match self{
+            FrameKind::Jsr=>"JSR",
+            FrameKind::Nmi=>"NMI",
+            FrameKind::Irq=>"IRQ",
+            FrameKind::Brk=>"BRK",
+        };
+        write!(f,"{}",name)
+    }
+}
+
+/// 1回の呼び出し
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Frame{
+    pub kind:FrameKind,
+    pub caller:u16,//JSR、BRKのアドレス。NMI,IRQなら割り込まれた命令
+    pub target:u16,//呼び出したサブルーチン、割り込みハンドラ
+    pub return_addr:u16,//RTS,RTIで戻るはずのアドレス
+    pub sp:u8,//戻り先を積んだ後のSP
+}
+
+impl fmt::Display for Frame{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        write!(
+            f,
+            "${:04X} from {} at ${:04X}, returns to ${:04X}",
+            self.target,self.kind,self.caller,self.return_addr
+        )
+    }
+}
+
+/// スタックの使い方が呼び出しと合わなかったところ。pcはJSRやRTS,RTIのアドレス
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Mismatch{
+    /// 戻り先が戻る前にスタックから捨てられた(PLAやTXSで巻き戻した、上書きした)
+    Abandoned{pc:u16,frame:Frame},
+    /// 戻り先が書き換えられていた
+    Redirected{pc:u16,frame:Frame,to:u16},
+    /// JSRにRTI、割り込みにRTSで戻った
+    WrongReturn{pc:u16,frame:Frame},
+    /// 対応する呼び出しのないRTS,RTI(PHA,PHA,RTSのジャンプテーブルなど)
+    Unmatched{pc:u16,to:u16},
+}
+
+impl fmt::Display for Mismatch{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        match self{
+            Mismatch::Abandoned{pc,frame}=>write!(f,"${:04X}: abandoned the call to {}",pc,frame),
+            Mismatch::Redirected{pc,frame,to}=>{
+                write!(f,"${:04X}: returned to ${:04X} instead of ${:04X}",pc,to,frame.return_addr)
+            }
+            Mismatch::WrongReturn{pc,frame}=>write!(f,"${:04X}: wrong return from the call to {}",pc,frame),
+            Mismatch::Unmatched{pc,to}=>write!(f,"${:04X}: returned to ${:04X} without a call",pc,to),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct CallStack{
+    frames:Vec<Frame>,//一番外側が先頭
+    mismatches:VecDeque<Mismatch>,
+    pushed:u64,//これまでに積んだ数。増えた分だけframesの末尾が新しい呼び出し
+}
+
+impl CallStack{
+    pub fn new()->Self{
+        Self::default()
+    }
+
+    /// 一番外側の呼び出しから順に
+    pub fn frames(&self)->&[Frame]{
+        &self.frames
+    }
+
+    /// 今いる呼び出しから外側へ順に
+    pub fn backtrace(&self)->Vec<Frame>{
+        self.frames.iter().rev().copied().collect()
+    }
+
+    pub fn depth(&self)->usize{
+        self.frames.len()
+    }
+
+    /// これまでに積んだ呼び出しの数(clearしても戻らない)
+    pub fn pushed(&self)->u64{
+        self.pushed
+    }
+
+    /// 最近のMismatchを古い順に取り出して消す
+    pub fn take_mismatches(&mut self)->Vec<Mismatch>{
+        self.mismatches.drain(..).collect()
+    }
+
+    pub fn clear(&mut self){
+        self.frames.clear();
+        self.mismatches.clear();
+    }
+
+    //戻り先を積んだところに前の呼び出しの戻り先があれば、もう戻れないので捨てる
+    pub(crate) fn push(&mut self,frame:Frame){
+        let bytes=if frame.kind.is_interrupt(){3}else{2};
+        while let Some(last)=self.frames.last().copied(){
+            if (last.sp as u16)>=frame.sp as u16+bytes{
+                break;
+            }
+            self.frames.pop();
+            self.mismatch(Mismatch::Abandoned{pc:frame.caller,frame:last});
+        }
+        self.frames.push(frame);
+        self.pushed+=1;
+    }
+
+    /// RTS,RTI(pcにある)が、SPがspのところから戻り先を下ろしてtoへ戻った
+    pub(crate) fn pop(&mut self,pc:u16,sp:u8,to:u16,rti:bool){
+        //SPより浅いところに戻り先がある呼び出しは、もう捨てられている
+        while let Some(last)=self.frames.last().copied(){
+            if last.sp>=sp{
+                break;
+            }
+            self.frames.pop();
+            self.mismatch(Mismatch::Abandoned{pc,frame:last});
+        }
+        match self.frames.last().copied(){
+            Some(frame) if frame.sp==sp=>{
+                self.frames.pop();
+                if frame.kind.is_interrupt()!=rti{
+                    self.mismatch(Mismatch::WrongReturn{pc,frame});
+                }else if frame.return_addr!=to{
+                    self.mismatch(Mismatch::Redirected{pc,frame,to});
+                }
+            }
+            //積んだ戻り先より後にPHAなどで置いたアドレスへ戻った
+            _=>self.mismatch(Mismatch::Unmatched{pc,to}),
+        }
+    }
+
+    fn mismatch(&mut self,mismatch:Mismatch){
+        if self.mismatches.len()>=MAX_MISMATCHES{
+            self.mismatches.pop_front();
+        }
+        self.mismatches.push_back(mismatch);
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::{Mem, CPU};
+
+    fn tracked(source:&str)->CPU{
+        let mut cpu=CPU::new();
+        cpu.load(assemble(source).unwrap()).unwrap();
+        cpu.call_stack=Some(CallStack::new());
+        cpu
+    }
+
+    fn run_to(cpu:&mut CPU,addr:u16){
+        while cpu.program_counter!=addr{
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_nested_calls_and_returns(){
+        let mut cpu=tracked("
+            jsr outer   ; $0600
+            brk         ; $0603
+        outer:
+            jsr inner   ; $0604
+            rts         ; $0607
+        inner:
+            nop         ; $0608
+            rts
+        ");
+        run_to(&mut cpu,0x0608);
+        let backtrace=cpu.backtrace();
+        assert_eq!(
+            backtrace,
+            vec![
+                Frame{kind:FrameKind::Jsr,caller:0x0604,target:0x0608,return_addr:0x0607,sp:0xF9},
+                Frame{kind:FrameKind::Jsr,caller:0x0600,target:0x0604,return_addr:0x0603,sp:0xFB},
+            ]
+        );
+        assert_eq!(backtrace[0].to_string(),"$0608 from JSR at $0604, returns to $0607");
+        cpu.run().unwrap();
+        let calls=cpu.call_stack.as_mut().unwrap();
+        assert_eq!(calls.depth(),0);
+        assert!(calls.take_mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_interrupt_frames(){
+        let mut cpu=tracked("
+            cli
+        loop:
+            jmp loop    ; $0601
+        handler:
+            jsr work    ; $0604
+            rti         ; $0607
+        work:
+            rts         ; $0608
+        ");
+        cpu.mem_write_u16(0xFFFE,0x0604);
+        cpu.step().unwrap();
+        cpu.set_irq(true);
+        //割り込んでからハンドラの最初の命令(JSR)まで進む
+        cpu.step().unwrap();
+        cpu.set_irq(false);
+        assert_eq!(cpu.program_counter,0x0608);
+        let backtrace=cpu.backtrace();
+        assert_eq!(backtrace.len(),2);
+        assert_eq!(backtrace[1],Frame{kind:FrameKind::Irq,caller:0x0601,target:0x0604,return_addr:0x0601,sp:0xFA});
+        assert_eq!(backtrace[0].sp,0xF8);
+        run_to(&mut cpu,0x0601);
+        let calls=cpu.call_stack.as_mut().unwrap();
+        assert_eq!(calls.depth(),0);
+        assert!(calls.take_mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_brk_frame(){
+        let mut cpu=tracked("
+            brk         ; $0600
+            nop
+            nop         ; $0602
+        handler:
+            rti         ; $0603
+        ");
+        cpu.halt_on_brk=false;
+        cpu.mem_write_u16(0xFFFE,0x0603);
+        cpu.step().unwrap();
+        assert_eq!(cpu.backtrace()[0].kind,FrameKind::Brk);
+        assert_eq!(cpu.backtrace()[0].caller,0x0600);
+        assert_eq!(cpu.backtrace()[0].return_addr,0x0602);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter,0x0602);
+        assert!(cpu.backtrace().is_empty());
+    }
+
+    #[test]
+    fn test_jump_table_is_unmatched(){
+        //PHA,PHA,RTSで飛んだ先から、JSRの呼び出し元へ戻る
+        let mut cpu=tracked("
+            jsr dispatch    ; $0600
+            brk             ; $0603
+        dispatch:
+            lda #$06        ; $0604
+            pha
+            lda #$0A
+            pha
+            rts             ; $060A
+        target:
+            rts             ; $060B
+        ");
+        run_to(&mut cpu,0x0603);
+        let calls=cpu.call_stack.as_mut().unwrap();
+        assert_eq!(calls.take_mismatches(),vec![Mismatch::Unmatched{pc:0x060A,to:0x060B}]);
+        assert_eq!(calls.depth(),0);
+    }
+
+    #[test]
+    fn test_abandoned_and_redirected(){
+        let mut cpu=tracked("
+            jsr first       ; $0600
+            brk             ; $0603
+        first:
+            jsr second      ; $0604
+            brk
+        second:
+            pla             ; $0608 戻り先を捨ててfirstの呼び出し元へ戻る
+            pla
+            rts             ; $060A
+        ");
+        run_to(&mut cpu,0x0603);
+        let calls=cpu.call_stack.as_mut().unwrap();
+        let mismatches=calls.take_mismatches();
+        assert_eq!(mismatches.len(),1);
+        assert!(matches!(mismatches[0],Mismatch::Abandoned{pc:0x060A,frame:Frame{caller:0x0604,..}}));
+        assert_eq!(calls.depth(),0);
+
+        //戻り先を書き換える
+        let mut cpu=tracked("
+            jsr sub         ; $0600
+            brk
+            brk             ; $0604
+        sub:
+            inc $01FC       ; $0605
+            rts             ; $0608
+        ");
+        run_to(&mut cpu,0x0604);
+        let calls=cpu.call_stack.as_mut().unwrap();
+        let mismatches=calls.take_mismatches();
+        assert_eq!(mismatches.len(),1);
+        assert_eq!(mismatches[0].to_string(),"$0608: returned to $0604 instead of $0603");
+    }
+
+    #[test]
+    fn test_mismatches_are_bounded(){
+        let mut calls=CallStack::new();
+        for i in 0..100{
+            calls.pop(i,0xFD,0,false);
+        }
+        let mismatches=calls.take_mismatches();
+        assert_eq!(mismatches.len(),MAX_MISMATCHES);
+        assert_eq!(mismatches[0],Mismatch::Unmatched{pc:100-MAX_MISMATCHES as u16,to:0});
+    }
+}
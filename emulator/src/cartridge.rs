@@ -0,0 +1,172 @@
+const NES_TAG:[u8;4]=[0x4E,0x45,0x53,0x1A];//"NES"+EOF
+const PRG_ROM_PAGE_SIZE:usize=16384;
+const CHR_ROM_PAGE_SIZE:usize=8192;
+const TRAINER_SIZE:usize=512;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Mirroring{
+    Vertical,
+    Horizontal,
+    FourScreen,
+}
+
+/// iNES形式のROMイメージ
+pub struct Rom{
+    pub prg_rom:Vec<u8>,
+    pub chr_rom:Vec<u8>,
+    pub mapper:u8,
+    pub screen_mirroring:Mirroring,
+}
+
+/// 先頭がiNESのヘッダになっているか
+pub fn is_ines(raw:&[u8])->bool{
+    raw.len()>=16&&raw[0..4]==NES_TAG
+}
+
+impl Rom{
+    pub fn new(raw:&[u8])->Result<Rom,String>{
+        if !is_ines(raw){
+            return Err("File is not in iNES file format".to_string());
+        }
+
+        let mapper=(raw[7]&0b1111_0000)|(raw[6]>>4);
+        //BusはPRG-ROMを$8000-$FFFFにそのまま(16KBなら2回)置くだけ
+        if mapper!=0{
+            return Err(format!("mapper {} is not supported (only NROM)",mapper));
+        }
+
+        let ines_ver=(raw[7]>>2)&0b11;
+        if ines_ver!=0{
+            return Err("NES2.0 format is not supported".to_string());
+        }
+
+        let four_screen=raw[6]&0b1000!=0;
+        let vertical_mirroring=raw[6]&0b1!=0;
+        let screen_mirroring=match (four_screen,vertical_mirroring){
+            (true,_)=>Mirroring::FourScreen,
+            (false,true)=>Mirroring::Vertical,
+            (false,false)=>Mirroring::Horizontal,
+        };
+
+        if raw[4]==0{
+            return Err("ROM has no PRG-ROM".to_string());
+        }
+        let prg_rom_size=raw[4] as usize*PRG_ROM_PAGE_SIZE;
+        let chr_rom_size=raw[5] as usize*CHR_ROM_PAGE_SIZE;
+
+        let skip_trainer=raw[6]&0b100!=0;
+
+        let prg_rom_start=16+if skip_trainer{TRAINER_SIZE}else{0};
+        let chr_rom_start=prg_rom_start+prg_rom_size;
+
+        if raw.len()<chr_rom_start+chr_rom_size{
+            return Err("ROM image is truncated".to_string());
+        }
+
+        Ok(Rom{
+            prg_rom:raw[prg_rom_start..(prg_rom_start+prg_rom_size)].to_vec(),
+            chr_rom:raw[chr_rom_start..(chr_rom_start+chr_rom_size)].to_vec(),
+            mapper,
+            screen_mirroring,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test{
+    use super::*;
+
+    pub struct TestRom{
+        pub header:Vec<u8>,
+        pub trainer:Option<Vec<u8>>,
+        pub prg_rom:Vec<u8>,
+        pub chr_rom:Vec<u8>,
+    }
+
+    pub fn create_rom(rom:TestRom)->Vec<u8>{
+        let mut result=Vec::new();
+        result.extend(rom.header);
+        if let Some(trainer)=rom.trainer{
+            result.extend(trainer);
+        }
+        result.extend(rom.prg_rom);
+        result.extend(rom.chr_rom);
+        result
+    }
+
+    pub fn test_rom(prg_rom:Vec<u8>)->Rom{
+        let pages=prg_rom.len()/PRG_ROM_PAGE_SIZE;
+        let raw=create_rom(TestRom{
+            header:vec![0x4E,0x45,0x53,0x1A,pages as u8,0x01,0x01,00,00,00,00,00,00,00,00,00],
+            trainer:None,
+            prg_rom,
+            chr_rom:vec![2;CHR_ROM_PAGE_SIZE],
+        });
+        Rom::new(&raw).unwrap()
+    }
+
+    #[test]
+    fn test(){
+        let raw=create_rom(TestRom{
+            header:vec![0x4E,0x45,0x53,0x1A,0x02,0x01,0x01,00,00,00,00,00,00,00,00,00],
+            trainer:None,
+            prg_rom:vec![1;2*PRG_ROM_PAGE_SIZE],
+            chr_rom:vec![2;CHR_ROM_PAGE_SIZE],
+        });
+        let rom=Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.chr_rom,vec![2;CHR_ROM_PAGE_SIZE]);
+        assert_eq!(rom.prg_rom,vec![1;2*PRG_ROM_PAGE_SIZE]);
+        assert_eq!(rom.mapper,0);
+        assert_eq!(rom.screen_mirroring,Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_with_trainer(){
+        let raw=create_rom(TestRom{
+            header:vec![0x4E,0x45,0x53,0x1A,0x02,0x01,0x01|0b100,00,00,00,00,00,00,00,00,00],
+            trainer:Some(vec![0;TRAINER_SIZE]),
+            prg_rom:vec![1;2*PRG_ROM_PAGE_SIZE],
+            chr_rom:vec![2;CHR_ROM_PAGE_SIZE],
+        });
+        let rom=Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.chr_rom,vec![2;CHR_ROM_PAGE_SIZE]);
+        assert_eq!(rom.prg_rom,vec![1;2*PRG_ROM_PAGE_SIZE]);
+        assert_eq!(rom.mapper,0);
+    }
+
+    #[test]
+    fn test_nes2_is_not_supported(){
+        let raw=create_rom(TestRom{
+            header:vec![0x4E,0x45,0x53,0x1A,0x01,0x01,0x01,0x8,00,00,00,00,00,00,00,00],
+            trainer:None,
+            prg_rom:vec![1;PRG_ROM_PAGE_SIZE],
+            chr_rom:vec![2;CHR_ROM_PAGE_SIZE],
+        });
+        assert!(Rom::new(&raw).is_err());
+    }
+
+    #[test]
+    fn test_only_nrom_is_supported(){
+        let raw=create_rom(TestRom{
+            header:vec![0x4E,0x45,0x53,0x1A,0x02,0x01,0x31,00,00,00,00,00,00,00,00,00],
+            trainer:None,
+            prg_rom:vec![1;2*PRG_ROM_PAGE_SIZE],
+            chr_rom:vec![2;CHR_ROM_PAGE_SIZE],
+        });
+        assert_eq!(Rom::new(&raw).err(),Some("mapper 3 is not supported (only NROM)".to_string()));
+        assert!(is_ines(&raw));
+    }
+
+    #[test]
+    fn test_prg_rom_is_required(){
+        let raw=create_rom(TestRom{
+            header:vec![0x4E,0x45,0x53,0x1A,0x00,0x01,0x01,00,00,00,00,00,00,00,00,00],
+            trainer:None,
+            prg_rom:vec![],
+            chr_rom:vec![2;CHR_ROM_PAGE_SIZE],
+        });
+        assert_eq!(Rom::new(&raw).err(),Some("ROM has no PRG-ROM".to_string()));
+    }
+}
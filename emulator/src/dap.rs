@@ -0,0 +1,636 @@
+// Debug Adapter Protocol(DAP)のサーバ
+//
+// VS Codeなどのエディタと標準入出力でつなぎ、アセンブリのソースを1行ずつ追えるようにする。
+// launchで渡された.sをアセンブルし、Assembly::line_addressesを行とアドレスの対応表に使う。
+// 呼び出し履歴はCPUのシャドウコールスタックから作る
+use crate::asm;
+use crate::callstack::FrameKind;
+use crate::cpu::{CpuVariant, Mem};
+use crate::debugger::{Breakpoint, Debugger, StopReason};
+use crate::symbols::SymbolTable;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+const THREAD_ID:i64=1;
+
+//scopesで返すvariablesReference
+const REGISTERS_REF:i64=1;
+const ZERO_PAGE_REF:i64=2;
+const STACK_REF:i64=3;
+
+/// "Content-Length: n"のヘッダに続くJSONを1つ読む。入力が終わればNone
+pub fn read_message<R:BufRead>(reader:&mut R)->io::Result<Option<Value>>{
+    let mut length=None;
+    loop{
+        let mut line=String::new();
+        if reader.read_line(&mut line)?==0{
+            return Ok(None);
+        }
+        let line=line.trim_end();
+        if line.is_empty(){
+            if length.is_some(){
+                break;
+            }
+            continue;
+        }
+        if let Some(value)=line.strip_prefix("Content-Length:"){
+            let value=value.trim().parse::<usize>().map_err(|e|io::Error::new(ErrorKind::InvalidData,e))?;
+            length=Some(value);
+        }
+    }
+    let mut body=vec![0;length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e|io::Error::new(ErrorKind::InvalidData,e))
+}
+
+pub fn write_message<W:Write>(writer:&mut W,message:&Value)->io::Result<()>{
+    let body=message.to_string();
+    write!(writer,"Content-Length: {}\r\n\r\n{}",body.len(),body)?;
+    writer.flush()
+}
+
+//readMemoryの応答はbase64で返す
+fn base64(bytes:&[u8])->String{
+    const TABLE:&[u8;64]=b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text=String::new();
+    for chunk in bytes.chunks(3){
+        let n=(chunk[0] as u32)<<16|(*chunk.get(1).unwrap_or(&0) as u32)<<8|*chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4{
+            if i<=chunk.len(){
+                text.push(TABLE[(n>>(18-6*i)&0x3F) as usize] as char);
+            }else{
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+//"0x0200"、"$0200"、"0200"のどれでも16進として読む
+fn parse_address(text:&str)->Option<u16>{
+    let digits=text.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits,16).ok()
+}
+
+//launchしたソースと、行とアドレスの対応
+struct Program{
+    path:String,
+    lines:Vec<(usize,u16)>,//行番号順
+    addresses:HashMap<u16,usize>,//命令のアドレスから行
+    entry:u16,
+}
+
+pub struct DapServer{
+    pub debugger:Debugger,
+    pub symbols:SymbolTable,
+    program:Option<Program>,
+    requested_breakpoints:Vec<Value>,//setBreakpointsで来たもの。launchの前に来てもあとで置けるように覚えておく
+    seq:i64,
+    stop_on_entry:bool,
+    disconnected:bool,
+}
+
+impl Default for DapServer{
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+impl DapServer{
+    pub fn new()->Self{
+        DapServer{
+            debugger:Debugger::default(),
+            symbols:SymbolTable::new(),
+            program:None,
+            requested_breakpoints:vec![],
+            seq:0,
+            stop_on_entry:false,
+            disconnected:false,
+        }
+    }
+
+    /// disconnectか入力の終わりまで要求に答える。実行中にpauseが届くように、入力は別スレッドで読む
+    pub fn run<R:BufRead+Send+'static,W:Write>(&mut self,input:R,output:&mut W)->io::Result<()>{
+        let (sender,receiver)=mpsc::channel();
+        thread::spawn(move ||{
+            let mut input=input;
+            while let Ok(Some(message))=read_message(&mut input){
+                if sender.send(message).is_err(){
+                    break;
+                }
+            }
+        });
+        let mut pending=VecDeque::new();
+        while !self.disconnected{
+            let request=match pending.pop_front(){
+                Some(request)=>request,
+                None=>match receiver.recv(){
+                    Ok(request)=>request,
+                    Err(_)=>break,
+                },
+            };
+            //実行中に届いた要求は止まってから順に処理する
+            let mut interrupted=||{
+                let mut pause=false;
+                while let Ok(message)=receiver.try_recv(){
+                    pause|=message["command"]=="pause";
+                    pending.push_back(message);
+                }
+                pause
+            };
+            for message in self.handle(&request,&mut interrupted){
+                write_message(output,&message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 1つの要求を処理し、応答とイベントを送る順に返す。continueの途中でinterrupted()がtrueを返すと止める
+    pub fn handle(&mut self,request:&Value,interrupted:&mut dyn FnMut()->bool)->Vec<Value>{
+        let command=request["command"].as_str().unwrap_or("");
+        let arguments=&request["arguments"];
+        let mut events=vec![];
+        let result=match command{
+            "initialize"=>{
+                Ok(json!({
+                    "supportsConfigurationDoneRequest":true,
+                    "supportsConditionalBreakpoints":true,
+                    "supportsReadMemoryRequest":true,
+                }))
+            }
+            "launch"=>{
+                //プログラムを読み込めてからブレークポイントなどの設定を受け付ける
+                let result=self.launch(arguments);
+                if result.is_ok(){
+                    events.push(("initialized",json!({})));
+                }
+                result
+            }
+            "setBreakpoints"=>self.set_breakpoints(arguments),
+            "setExceptionBreakpoints"=>Ok(json!({})),
+            "configurationDone"=>{
+                if self.stop_on_entry{
+                    events.push(("stopped",stopped_body("entry",None)));
+                }else{
+                    events.extend(stop_events(self.debugger.resume_interruptible(interrupted)));
+                }
+                Ok(json!({}))
+            }
+            "threads"=>Ok(json!({"threads":[{"id":THREAD_ID,"name":"6502"}]})),
+            "stackTrace"=>Ok(self.stack_trace()),
+            "scopes"=>Ok(json!({"scopes":[
+                {"name":"Registers","variablesReference":REGISTERS_REF,"expensive":false},
+                {"name":"Zero Page","variablesReference":ZERO_PAGE_REF,"expensive":false},
+                {"name":"Stack","variablesReference":STACK_REF,"expensive":false},
+            ]})),
+            "variables"=>Ok(json!({"variables":self.variables(arguments["variablesReference"].as_i64().unwrap_or(0))})),
+            "continue"=>{
+                events.extend(stop_events(self.debugger.resume_interruptible(interrupted)));
+                Ok(json!({"allThreadsContinued":true}))
+            }
+            "next"=>{
+                events.extend(stop_events(self.debugger.step_over_interruptible(interrupted)));
+                Ok(json!({}))
+            }
+            "stepIn"=>{
+                events.extend(stop_events(self.debugger.step_into()));
+                Ok(json!({}))
+            }
+            "stepOut"=>{
+                events.extend(stop_events(self.debugger.step_out_interruptible(interrupted)));
+                Ok(json!({}))
+            }
+            "pause"=>Ok(json!({})),//止まっていなければcontinueの途中でもう止めてある
+            "readMemory"=>self.read_memory(arguments),
+            "disconnect"=>{
+                self.disconnected=true;
+                Ok(json!({}))
+            }
+            _=>Err(format!("unsupported request: {}",command)),
+        };
+
+        let mut messages=vec![self.response(request,result)];
+        for (event,body) in events{
+            messages.push(self.event(event,body));
+        }
+        messages
+    }
+
+    fn next_seq(&mut self)->i64{
+        self.seq+=1;
+        self.seq
+    }
+
+    fn response(&mut self,request:&Value,result:Result<Value,String>)->Value{
+        let seq=self.next_seq();
+        let mut response=json!({
+            "seq":seq,
+            "type":"response",
+            "request_seq":request["seq"],
+            "command":request["command"],
+            "success":result.is_ok(),
+        });
+        match result{
+            Ok(body)=>response["body"]=body,
+            Err(message)=>response["message"]=Value::String(message),
+        }
+        response
+    }
+
+    fn event(&mut self,event:&str,body:Value)->Value{
+        let seq=self.next_seq();
+        json!({"seq":seq,"type":"event","event":event,"body":body})
+    }
+
+    fn launch(&mut self,arguments:&Value)->Result<Value,String>{
+        let path=arguments["program"].as_str().ok_or("launch needs \"program\" (an assembly source)")?;
+        let source=fs::read_to_string(path).map_err(|e|format!("{}: {}",path,e))?;
+        let variant=match arguments["cpu"].as_str(){
+            Some(name)=>name.parse()?,
+            None=>CpuVariant::default(),
+        };
+        self.load_program(path,&source,variant)?;
+        self.stop_on_entry=arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(json!({}))
+    }
+
+    /// ソースをアセンブルして読み込む。ベクタ($FFFA~)まで含むならNESのようにリセットベクタから始める
+    pub fn load_program(&mut self,path:&str,source:&str,variant:CpuVariant)->Result<(),String>{
+        let assembly=asm::assemble_program_for(variant,source).map_err(|e|format!("{}: {}",path,e))?;
+        let mut debugger=Debugger::default();
+        debugger.cpu.variant=variant;
+        debugger.cpu.load_at(assembly.origin,&assembly.bytes).map_err(|e|e.to_string())?;
+        if assembly.origin as usize+assembly.bytes.len()>0xFFFD{
+            debugger.cpu.halt_on_brk=false;
+            debugger.cpu.power_on();
+        }else{
+            debugger.cpu.program_counter=assembly.origin;
+        }
+
+        let mut lines=assembly.line_addresses.clone();
+        lines.sort();
+        let addresses=lines.iter().map(|(line,addr)|(*addr,*line)).collect();
+        self.program=Some(Program{
+            path:path.to_string(),
+            lines,
+            addresses,
+            entry:debugger.cpu.program_counter,
+        });
+        self.symbols=SymbolTable::from_assembly(&assembly);
+        self.debugger=debugger;
+        self.resolve_breakpoints();
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self,arguments:&Value)->Result<Value,String>{
+        //ソースは1つなので、毎回すべて置き換える
+        self.requested_breakpoints=arguments["breakpoints"].as_array().cloned().unwrap_or_default();
+        Ok(json!({"breakpoints":self.resolve_breakpoints()}))
+    }
+
+    //requested_breakpointsを今のプログラムのアドレスに置き直す。
+    //命令のない行(コメントやラベルだけの行)は、その後で最初に命令がある行に置く
+    fn resolve_breakpoints(&mut self)->Vec<Value>{
+        let mut breakpoints=vec![];
+        let mut results=vec![];
+        for (id,requested) in self.requested_breakpoints.iter().enumerate(){
+            let line=requested["line"].as_u64().unwrap_or(0) as usize;
+            let found=self
+                .program
+                .as_ref()
+                .and_then(|program|program.lines.iter().find(|(l,_)|*l>=line).copied());
+            let condition=match requested["condition"].as_str().filter(|condition|!condition.trim().is_empty()){
+                Some(condition)=>match condition.parse(){
+                    Ok(condition)=>Some(condition),
+                    Err(message)=>{
+                        results.push(json!({"id":id,"verified":false,"line":line,"message":message}));
+                        continue;
+                    }
+                },
+                None=>None,
+            };
+            match found{
+                Some((line,addr))=>{
+                    breakpoints.push(Breakpoint{addr,condition});
+                    results.push(json!({"id":id,"verified":true,"line":line}));
+                }
+                None=>results.push(json!({"id":id,"verified":false,"line":line,"message":"no instruction at or after this line"})),
+            }
+        }
+        self.debugger.breakpoints=breakpoints;
+        results
+    }
+
+    fn stack_trace(&mut self)->Value{
+        //内側の呼び出しから順に(その中で止まっているアドレス,サブルーチンの先頭,どう入ったか)
+        let entry=self.program.as_ref().map(|program|program.entry).unwrap_or(0);
+        let mut frames=vec![];
+        let mut pc=self.debugger.cpu.program_counter;
+        for frame in self.debugger.cpu.backtrace(){
+            frames.push((pc,frame.target,Some(frame.kind)));
+            pc=frame.caller;
+        }
+        frames.push((pc,entry,None));
+
+        let frames:Vec<Value>=frames
+            .iter()
+            .enumerate()
+            .map(|(id,(pc,routine,kind))|{
+                let mut name=match self.symbols.name_at(*routine){
+                    Some(name)=>name.to_string(),
+                    None=>format!("${:04X}",routine),
+                };
+                //割り込みハンドラはどの割り込みかも見せる
+                if let Some(kind)=kind.filter(|kind|*kind!=FrameKind::Jsr){
+                    name=format!("{} ({})",name,kind);
+                }
+                let mut frame=json!({
+                    "id":id,
+                    "name":name,
+                    "line":0,
+                    "column":0,
+                    "instructionPointerReference":format!("0x{:04X}",pc),
+                });
+                if let Some(program)=&self.program{
+                    if let Some(line)=program.addresses.get(pc){
+                        let name=Path::new(&program.path).file_name().map(|name|name.to_string_lossy().into_owned());
+                        frame["line"]=json!(line);
+                        frame["column"]=json!(1);
+                        frame["source"]=json!({"name":name,"path":program.path});
+                    }
+                }
+                frame
+            })
+            .collect();
+        json!({"stackFrames":frames,"totalFrames":frames.len()})
+    }
+
+    fn variables(&mut self,reference:i64)->Vec<Value>{
+        let byte=|name:String,value:u8|json!({"name":name,"value":format!("${:02X}",value),"variablesReference":0});
+        match reference{
+            REGISTERS_REF=>{
+                let r=self.debugger.cpu.registers();
+                vec![
+                    byte("A".to_string(),r.a),
+                    byte("X".to_string(),r.x),
+                    byte("Y".to_string(),r.y),
+                    json!({"name":"P","value":format!("${:02X} [{}]",r.p.bits(),r.p.letters()),"variablesReference":0}),
+                    byte("SP".to_string(),r.sp),
+                    json!({"name":"PC","value":self.symbols.label(r.pc),"variablesReference":0,"memoryReference":format!("0x{:04X}",r.pc)}),
+                    json!({"name":"cycles","value":r.cycles.to_string(),"variablesReference":0}),
+                ]
+            }
+            ZERO_PAGE_REF=>(0..=0xFFu16).map(|addr|byte(format!("${:02X}",addr),self.debugger.cpu.peek(addr))).collect(),
+            STACK_REF=>{
+                let sp=self.debugger.cpu.registers().sp as u16;
+                (0x0100+sp+1..=0x01FF).map(|addr|byte(format!("${:04X}",addr),self.debugger.cpu.peek(addr))).collect()
+            }
+            _=>vec![],
+        }
+    }
+
+    fn read_memory(&mut self,arguments:&Value)->Result<Value,String>{
+        let reference=arguments["memoryReference"].as_str().ok_or("readMemory needs memoryReference")?;
+        let base=parse_address(reference).ok_or_else(||format!("invalid memory reference: {}",reference))?;
+        let start=(base as i64).saturating_add(arguments["offset"].as_i64().unwrap_or(0));
+        let count=arguments["count"].as_u64().unwrap_or(0).min(i64::MAX as u64) as i64;
+        //64KBの外は読めないバイトとして数える
+        let end=start.saturating_add(count).clamp(0,0x10000);
+        let start=start.clamp(0,0x10000);
+        let bytes:Vec<u8>=(start..end).map(|addr|self.debugger.cpu.peek(addr as u16)).collect();
+        Ok(json!({
+            "address":format!("0x{:04X}",start),
+            "data":base64(&bytes),
+            "unreadableBytes":count-bytes.len() as i64,
+        }))
+    }
+}
+
+//止まった理由を送るイベントにする。BRKまで来たらプログラムの終わりとして扱う
+fn stop_events(reason:StopReason)->Vec<(&'static str,Value)>{
+    let kind=match reason{
+        StopReason::Halted{..}=>return vec![("terminated",json!({})),("exited",json!({"exitCode":0}))],
+        StopReason::Interrupted{..}=>return vec![("stopped",stopped_body("pause",None))],
+        StopReason::Breakpoint{..}=>"breakpoint",
+        StopReason::Watchpoint{..}=>"data breakpoint",
+        StopReason::Error(_)=>"exception",
+        _=>"step",
+    };
+    vec![("stopped",stopped_body(kind,Some(reason.to_string())))]
+}
+
+fn stopped_body(reason:&str,description:Option<String>)->Value{
+    let mut body=json!({"reason":reason,"threadId":THREAD_ID,"allThreadsStopped":true});
+    if let Some(description)=description{
+        body["description"]=json!(description);
+    }
+    body
+}
+
+/// 標準入出力でDAPのセッションを1つ処理する
+pub fn serve()->io::Result<()>{
+    let stdout=io::stdout();
+    let mut out=stdout.lock();
+    DapServer::new().run(BufReader::new(io::stdin()),&mut out)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+
+    const SOURCE:&str="; テスト用
+start:
+    ldx #0
+loop:
+    inx
+    jsr sub
+    cpx #3
+    bne loop
+    brk
+
+sub:
+    lda #$42
+    rts
+";
+
+    fn server()->DapServer{
+        let mut server=DapServer::new();
+        server.load_program("/src/test.s",SOURCE,CpuVariant::Ricoh2A03).unwrap();
+        server
+    }
+
+    fn request(server:&mut DapServer,command:&str,arguments:Value)->Vec<Value>{
+        server.handle(&json!({"seq":1,"type":"request","command":command,"arguments":arguments}),&mut ||false)
+    }
+
+    #[test]
+    fn test_message_framing(){
+        let mut out=vec![];
+        write_message(&mut out,&json!({"seq":1})).unwrap();
+        assert_eq!(out,b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+        let mut input=&out[..];
+        assert_eq!(read_message(&mut input).unwrap(),Some(json!({"seq":1})));
+        assert_eq!(read_message(&mut input).unwrap(),None);
+    }
+
+    #[test]
+    fn test_base64(){
+        assert_eq!(base64(b""),"");
+        assert_eq!(base64(b"f"),"Zg==");
+        assert_eq!(base64(b"fo"),"Zm8=");
+        assert_eq!(base64(b"foo"),"Zm9v");
+        assert_eq!(base64(&[0xff,0xfe,0x00,0x01]),"//4AAQ==");
+    }
+
+    #[test]
+    fn test_initialize_and_unknown_request(){
+        let mut server=server();
+        let messages=request(&mut server,"initialize",json!({}));
+        assert_eq!(messages[0]["success"],true);
+        assert_eq!(messages[0]["body"]["supportsConfigurationDoneRequest"],true);
+        //initializedはlaunchが通ってから
+        assert_eq!(messages.len(),1);
+        let messages=request(&mut server,"frobnicate",json!({}));
+        assert_eq!(messages[0]["success"],false);
+        let messages=request(&mut server,"launch",json!({"program":"/nonexistent/prog.s"}));
+        assert_eq!(messages[0]["success"],false);
+        assert_eq!(messages.len(),1);
+
+        let path=env::temp_dir().join(format!("dap_launch_{}.s",std::process::id()));
+        fs::write(&path,SOURCE).unwrap();
+        let messages=request(&mut server,"launch",json!({"program":path.to_str().unwrap()}));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(messages[0]["success"],true);
+        assert_eq!(messages[1]["event"],"initialized");
+    }
+
+    #[test]
+    fn test_breakpoints_set_before_launch(){
+        let mut server=DapServer::new();
+        let messages=request(&mut server,"setBreakpoints",json!({"breakpoints":[{"line":5}]}));
+        assert_eq!(messages[0]["body"]["breakpoints"][0]["verified"],false);
+        server.load_program("/src/test.s",SOURCE,CpuVariant::Ricoh2A03).unwrap();
+        assert_eq!(server.debugger.breakpoints,vec![Breakpoint::new(0x0602)]);
+    }
+
+    #[test]
+    fn test_breakpoints_map_lines(){
+        let mut server=server();
+        let messages=request(&mut server,"setBreakpoints",json!({
+            "source":{"path":"/src/test.s"},
+            "breakpoints":[{"line":4},{"line":7,"condition":"x==2"},{"line":100},{"line":5,"condition":"q"}],
+        }));
+        let results=&messages[0]["body"]["breakpoints"];
+        //4行目はラベルだけなので5行目のINXに置く
+        assert_eq!(results[0],json!({"id":0,"verified":true,"line":5}));
+        assert_eq!(results[1],json!({"id":1,"verified":true,"line":7}));
+        assert_eq!(results[2]["verified"],false);
+        assert_eq!(results[3]["verified"],false);
+        assert_eq!(server.debugger.breakpoints.len(),2);
+        assert_eq!(server.debugger.breakpoints[0].addr,0x0602);
+    }
+
+    #[test]
+    fn test_run_to_breakpoint_and_inspect(){
+        let mut server=server();
+        request(&mut server,"setBreakpoints",json!({"breakpoints":[{"line":13}]}));
+        let messages=request(&mut server,"configurationDone",json!({}));
+        assert_eq!(messages[1]["event"],"stopped");
+        assert_eq!(messages[1]["body"]["reason"],"breakpoint");
+
+        let messages=request(&mut server,"stackTrace",json!({"threadId":1}));
+        let frames=&messages[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"],"sub");
+        assert_eq!(frames[0]["line"],13);
+        assert_eq!(frames[0]["source"]["name"],"test.s");
+        assert_eq!(frames[1]["name"],"start");
+        assert_eq!(frames[1]["line"],6);
+        assert_eq!(messages[0]["body"]["totalFrames"],2);
+
+        let messages=request(&mut server,"variables",json!({"variablesReference":REGISTERS_REF}));
+        let registers=&messages[0]["body"]["variables"];
+        assert_eq!(registers[1],json!({"name":"X","value":"$01","variablesReference":0}));
+        assert_eq!(registers[5]["value"],"$060D");
+
+        let messages=request(&mut server,"variables",json!({"variablesReference":STACK_REF}));
+        let stack=&messages[0]["body"]["variables"];
+        assert_eq!(stack[0],json!({"name":"$01FC","value":"$05","variablesReference":0}));
+        assert_eq!(stack[1],json!({"name":"$01FD","value":"$06","variablesReference":0}));
+    }
+
+    #[test]
+    fn test_stepping_and_termination(){
+        let mut server=server();
+        server.stop_on_entry=true;
+        let messages=request(&mut server,"configurationDone",json!({}));
+        assert_eq!(messages[1]["body"]["reason"],"entry");
+        request(&mut server,"next",json!({"threadId":1}));
+        request(&mut server,"next",json!({"threadId":1}));
+        let messages=request(&mut server,"next",json!({"threadId":1}));
+        assert_eq!(messages[1]["body"]["reason"],"step");
+        assert_eq!(server.debugger.cpu.program_counter,0x0606);
+        assert_eq!(server.debugger.cpu.register_a,0x42);
+        let messages=request(&mut server,"stepIn",json!({"threadId":1}));
+        assert_eq!(messages[1]["event"],"stopped");
+        request(&mut server,"stepIn",json!({"threadId":1}));
+        let messages=request(&mut server,"continue",json!({"threadId":1}));
+        assert_eq!(messages[1]["event"],"terminated");
+        assert_eq!(messages[2]["event"],"exited");
+    }
+
+    #[test]
+    fn test_pause_while_running(){
+        let mut server=DapServer::new();
+        server.load_program("loop.s","loop:\n  jmp loop\n",CpuVariant::Ricoh2A03).unwrap();
+        let mut polls=0;
+        let messages=server.handle(&json!({"seq":5,"command":"continue"}),&mut ||{
+            polls+=1;
+            polls==2
+        });
+        assert_eq!(messages[0]["request_seq"],5);
+        assert_eq!(messages[1]["body"]["reason"],"pause");
+    }
+
+    #[test]
+    fn test_load_65c02_program(){
+        let mut server=DapServer::new();
+        assert!(server.load_program("bra.s","loop:\n  bra loop\n",CpuVariant::Ricoh2A03).is_err());
+        server.load_program("bra.s","loop:\n  bra loop\n",CpuVariant::Wdc65C02).unwrap();
+        assert_eq!(server.debugger.cpu.mem_read(0x0600),0x80);
+    }
+
+    #[test]
+    fn test_next_over_long_subroutine(){
+        let mut server=DapServer::new();
+        let source="    jsr slow\n    brk\nslow:\n    ldy #0\n    ldx #0\nloop:\n    dex\n    bne loop\n    dey\n    bne loop\n    rts\n";
+        server.load_program("slow.s",source,CpuVariant::Ricoh2A03).unwrap();
+        //pauseが来ないまま何度確かめても、nextはJSRの次の行で止まる
+        let mut polls=0;
+        let messages=server.handle(&json!({"seq":1,"command":"next"}),&mut ||{
+            polls+=1;
+            false
+        });
+        assert!(polls>1,"{}",polls);
+        assert_eq!(messages[1]["body"]["reason"],"step");
+        assert_eq!(server.debugger.cpu.program_counter,0x0603);
+    }
+
+    #[test]
+    fn test_read_memory(){
+        let mut server=server();
+        let messages=request(&mut server,"readMemory",json!({"memoryReference":"0x0600","offset":2,"count":2}));
+        assert_eq!(messages[0]["body"],json!({"address":"0x0602","data":"6CA=","unreadableBytes":0}));
+        let messages=request(&mut server,"readMemory",json!({"memoryReference":"0xFFFF","count":2}));
+        assert_eq!(messages[0]["body"]["unreadableBytes"],1);
+        let messages=request(&mut server,"readMemory",json!({"memoryReference":"0x0600","offset":i64::MAX,"count":u64::MAX}));
+        assert_eq!(messages[0]["body"]["unreadableBytes"],i64::MAX);
+        let messages=request(&mut server,"readMemory",json!({"memoryReference":"0x0600","offset":i64::MIN,"count":2}));
+        assert_eq!(messages[0]["body"]["unreadableBytes"],2);
+    }
+}
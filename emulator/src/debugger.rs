@@ -0,0 +1,543 @@
+// ブレークポイント、ウォッチポイント、ステップ実行で止めながらCPUを動かす
+//
+// CPUのバスをWatchBusで包み、命令を実行している間のバスアクセスをウォッチポイントと照らし合わせる。
+// デバッガからメモリを覗くときはMem::peekを使うので、実行中でも引っかからない
+use crate::bus::{BusAccess, BusCycle, FlatMemory, RamPattern};
+use crate::callstack::CallStack;
+use crate::cpu::{CpuError, CpuRegisters, InterruptLines, Mem, CPU};
+use std::fmt;
+use std::str::FromStr;
+
+const JSR:u8=0x20;
+const RTS:u8=0x60;
+const RTI:u8=0x40;
+
+/// *_interruptibleで、この命令数ごとにinterruptedを呼んで止めるか確かめる
+pub const POLL_INSTRUCTIONS:u64=10_000;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Register{
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+}
+
+impl Register{
+    fn value(&self,registers:&CpuRegisters)->u16{
+        match self{
+            Register::A=>registers.a as u16,
+            Register::X=>registers.x as u16,
+            Register::Y=>registers.y as u16,
+            Register::P=>registers.p.bits() as u16,
+            Register::SP=>registers.sp as u16,
+            Register::PC=>registers.pc,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Comparison{
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+//長い演算子から順に探す
+const COMPARISONS:[(&str,Comparison);7]=[
+    ("==",Comparison::Eq),
+    ("!=",Comparison::Ne),
+    ("<=",Comparison::Le),
+    (">=",Comparison::Ge),
+    ("<",Comparison::Lt),
+    (">",Comparison::Gt),
+    ("=",Comparison::Eq),
+];
+
+/// ブレークポイントの条件。"x==$10"や"a>=3"のように書く
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Condition{
+    pub register:Register,
+    pub comparison:Comparison,
+    pub value:u16,
+}
+
+impl Condition{
+    pub fn matches(&self,registers:&CpuRegisters)->bool{
+        let actual=self.register.value(registers);
+        match self.comparison{
+            Comparison::Eq=>actual==self.value,
+            Comparison::Ne=>actual!=self.value,
+            Comparison::Lt=>actual<self.value,
+            Comparison::Le=>actual<=self.value,
+            Comparison::Gt=>actual>self.value,
+            Comparison::Ge=>actual>=self.value,
+        }
+    }
+}
+
+impl FromStr for Condition{
+    type Err=String;
+
+    fn from_str(text:&str)->Result<Self,Self::Err>{
+        let (index,symbol,comparison)=COMPARISONS
+            .iter()
+            .find_map(|(symbol,comparison)|text.find(symbol).map(|index|(index,*symbol,*comparison)))
+            .ok_or_else(||format!("condition needs a comparison (==, !=, <, <=, >, >=): {}",text))?;
+        let register=match text[..index].trim().to_ascii_lowercase().as_str(){
+            "a"=>Register::A,
+            "x"=>Register::X,
+            "y"=>Register::Y,
+            "p"=>Register::P,
+            "sp"=>Register::SP,
+            "pc"=>Register::PC,
+            other=>return Err(format!("unknown register: {}",other)),
+        };
+        let value=parse_number(text[index+symbol.len()..].trim())
+            .ok_or_else(||format!("invalid value in condition: {}",text))?;
+        Ok(Condition{register,comparison,value})
+    }
+}
+
+impl fmt::Display for Condition{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        let register=match self.register{
+            Register::A=>"a",
+            Register::X=>"x",
+            Register::Y=>"y",
+            Register::P=>"p",
+            Register::SP=>"sp",
+            Register::PC=>"pc",
+        };
+        let comparison=match self.comparison{
+            Comparison::Eq=>"==",
+            Comparison::Ne=>"!=",
+            Comparison::Lt=>"<",
+            Comparison::Le=>"<=",
+            Comparison::Gt=>">",
+            Comparison::Ge=>">=",
+        };
+        write!(f,"{}{}${:02X}",register,comparison,self.value)
+    }
+}
+
+//"$10"と"0x10"は16進、それ以外は10進
+fn parse_number(text:&str)->Option<u16>{
+    if let Some(hex)=text.strip_prefix('$').or_else(||text.strip_prefix("0x")){
+        u16::from_str_radix(hex,16).ok()
+    }else{
+        text.parse().ok()
+    }
+}
+
+/// addrの命令を実行する直前に止まる。conditionがあればそれが成り立つときだけ
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Breakpoint{
+    pub addr:u16,
+    pub condition:Option<Condition>,
+}
+
+impl Breakpoint{
+    pub fn new(addr:u16)->Self{
+        Breakpoint{addr,condition:None}
+    }
+
+    fn hit(&self,registers:&CpuRegisters)->bool{
+        registers.pc==self.addr&&self.condition.is_none_or(|condition|condition.matches(registers))
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum WatchKind{
+    Read,
+    Write,
+    Access,//読み書きどちらでも
+}
+
+/// start~end(endも含む)へのアクセスで、その命令が終わったところで止まる。ダミーリードも数える
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Watchpoint{
+    pub start:u16,
+    pub end:u16,
+    pub kind:WatchKind,
+}
+
+impl Watchpoint{
+    fn hit(&self,cycle:&BusCycle)->bool{
+        let kind=match (self.kind,cycle.access){
+            (WatchKind::Access,_)=>true,
+            (WatchKind::Read,access)=>access==BusAccess::Read,
+            (WatchKind::Write,access)=>access==BusAccess::Write,
+        };
+        kind&&(self.start..=self.end).contains(&cycle.addr)
+    }
+}
+
+/// ウォッチポイントを調べるバス。armedの間だけ調べる
+pub struct WatchBus<M:Mem>{
+    pub inner:M,
+    pub watchpoints:Vec<Watchpoint>,
+    armed:bool,
+    hit:Option<(usize,BusCycle)>,//最初に引っかかったウォッチポイントとアクセス
+}
+
+impl<M:Mem> WatchBus<M>{
+    pub fn new(inner:M)->Self{
+        WatchBus{inner,watchpoints:vec![],armed:false,hit:None}
+    }
+
+    fn check(&mut self,cycle:BusCycle){
+        if self.hit.is_some(){
+            return;
+        }
+        if let Some(index)=self.watchpoints.iter().position(|watchpoint|watchpoint.hit(&cycle)){
+            self.hit=Some((index,cycle));
+        }
+    }
+}
+
+impl<M:Mem> Mem for WatchBus<M>{
+    fn mem_read(&mut self,addr:u16)->u8{
+        let value=self.inner.mem_read(addr);
+        if self.armed{
+            self.check(BusCycle{addr,value,access:BusAccess::Read});
+        }
+        value
+    }
+
+    fn mem_write(&mut self,addr:u16,data:u8){
+        self.inner.mem_write(addr,data);
+        if self.armed{
+            self.check(BusCycle{addr,value:data,access:BusAccess::Write});
+        }
+    }
+
+    //ウォッチポイントには引っかからない
+    fn peek(&mut self,addr:u16)->u8{
+        self.inner.peek(addr)
+    }
+
+    fn fill_ram(&mut self,pattern:RamPattern){
+        self.inner.fill_ram(pattern);
+    }
+
+    fn interrupt_lines(&mut self)->Option<InterruptLines>{
+        self.inner.interrupt_lines()
+    }
+}
+
+/// 実行が止まった理由
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum StopReason{
+    Step{pc:u16},//ステップ実行が終わった
+    Reached{pc:u16},//run_untilの行き先に着いた
+    Breakpoint{index:usize,pc:u16},
+    Watchpoint{index:usize,cycle:BusCycle,pc:u16},//pcはアクセスした命令のアドレス
+    Halted{pc:u16},//halt_on_brkでBRKに到達した
+    Limit{pc:u16},//step_limitの命令数を実行した
+    Interrupted{pc:u16},//interrupted()がtrueを返した
+    Error(CpuError),
+}
+
+impl fmt::Display for StopReason{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        match self{
+            StopReason::Step{pc}=>write!(f,"stepped to ${:04X}",pc),
+            StopReason::Reached{pc}=>write!(f,"reached ${:04X}",pc),
+            StopReason::Breakpoint{index,pc}=>write!(f,"breakpoint {} at ${:04X}",index,pc),
+            StopReason::Watchpoint{index,cycle,pc}=>{
+                write!(f,"watchpoint {}: {} by the instruction at ${:04X}",index,cycle,pc)
+            }
+            StopReason::Halted{pc}=>write!(f,"BRK at ${:04X}",pc),
+            StopReason::Limit{pc}=>write!(f,"stopped at ${:04X} after the step limit",pc),
+            StopReason::Interrupted{pc}=>write!(f,"interrupted at ${:04X}",pc),
+            StopReason::Error(error)=>write!(f,"{}",error),
+        }
+    }
+}
+
+pub struct Debugger<M:Mem=FlatMemory>{
+    pub cpu:CPU<WatchBus<M>>,
+    pub breakpoints:Vec<Breakpoint>,
+    pub step_limit:Option<u64>,//run系で1回に実行する命令数の上限。Noneなら止まるまで回る
+}
+
+impl Default for Debugger{
+    fn default()->Self{
+        Debugger::new(FlatMemory::new())
+    }
+}
+
+impl<M:Mem> Debugger<M>{
+    pub fn new(bus:M)->Self{
+        let mut cpu=CPU::with_bus(WatchBus::new(bus));
+        cpu.call_stack=Some(CallStack::new());
+        Debugger{
+            cpu,
+            breakpoints:vec![],
+            step_limit:None,
+        }
+    }
+
+    pub fn watchpoints(&mut self)->&mut Vec<Watchpoint>{
+        &mut self.cpu.bus.watchpoints
+    }
+
+    /// 1命令だけ実行する
+    pub fn step_into(&mut self)->StopReason{
+        self.execute(|_,_,_|true,step,&mut ||false)
+    }
+
+    /// JSRならサブルーチンから戻ってくるまで実行する。それ以外はstep_intoと同じ
+    pub fn step_over(&mut self)->StopReason{
+        self.step_over_interruptible(&mut ||false)
+    }
+
+    /// step_overと同じ。POLL_INSTRUCTIONSごとにinterrupted()がtrueを返せばそこで止まる
+    pub fn step_over_interruptible(&mut self,interrupted:&mut dyn FnMut()->bool)->StopReason{
+        let pc=self.cpu.program_counter;
+        if self.cpu.peek(pc)!=JSR{
+            return self.step_into();
+        }
+        let sp=self.cpu.stack_pointer;
+        let ret=pc.wrapping_add(3);
+        //再帰呼び出しで同じアドレスに戻ったときは、SPがまだ深いので止まらない
+        self.execute(|cpu,_,_|cpu.program_counter==ret&&cpu.stack_pointer>=sp,step,interrupted)
+    }
+
+    /// 今のサブルーチン(または割り込みハンドラ)からRTS,RTIで抜けるまで実行する
+    pub fn step_out(&mut self)->StopReason{
+        self.step_out_interruptible(&mut ||false)
+    }
+
+    /// step_outと同じ。POLL_INSTRUCTIONSごとにinterrupted()がtrueを返せばそこで止まる
+    pub fn step_out_interruptible(&mut self,interrupted:&mut dyn FnMut()->bool)->StopReason{
+        let sp=self.cpu.stack_pointer;
+        //中で呼んだサブルーチンのRTSはSPが今より深いところで実行される
+        self.execute(|_,code,sp_before|(code==RTS||code==RTI)&&sp_before>=sp,step,interrupted)
+    }
+
+    /// ブレークポイントなどで止まるまで実行する
+    pub fn resume(&mut self)->StopReason{
+        self.resume_interruptible(&mut ||false)
+    }
+
+    /// resumeと同じ。POLL_INSTRUCTIONSごとにinterrupted()がtrueを返せばそこで止まる
+    pub fn resume_interruptible(&mut self,interrupted:&mut dyn FnMut()->bool)->StopReason{
+        self.execute(|_,_,_|false,step,interrupted)
+    }
+
+    /// addrに着くか、ブレークポイントなどで止まるまで実行する
+    pub fn run_until(&mut self,addr:u16)->StopReason{
+        self.execute(|cpu,_,_|cpu.program_counter==addr,|pc|StopReason::Reached{pc},&mut ||false)
+    }
+
+    //done(実行後のCPU,実行したオペコード,実行前のSP)がtrueになると、そのときのPCをreasonに渡して止まる。
+    //最初の命令は今止まっているところなので、ブレークポイントを見ない
+    fn execute<F>(&mut self,mut done:F,reason:fn(u16)->StopReason,interrupted:&mut dyn FnMut()->bool)->StopReason
+    where
+        F:FnMut(&CPU<WatchBus<M>>,u8,u8)->bool,
+    {
+        let mut executed=0;
+        loop{
+            let pc=self.cpu.program_counter;
+            if executed>0{
+                let registers=self.cpu.registers();
+                if let Some(index)=self.breakpoints.iter().position(|breakpoint|breakpoint.hit(&registers)){
+                    return StopReason::Breakpoint{index,pc};
+                }
+                if self.step_limit.is_some_and(|limit|executed>=limit){
+                    return StopReason::Limit{pc};
+                }
+                if executed%POLL_INSTRUCTIONS==0&&interrupted(){
+                    return StopReason::Interrupted{pc};
+                }
+            }
+            let code=self.cpu.peek(pc);
+            let sp=self.cpu.stack_pointer;
+
+            self.cpu.bus.armed=true;
+            let result=self.cpu.step();
+            self.cpu.bus.armed=false;
+            executed+=1;
+
+            match result{
+                Err(error)=>return StopReason::Error(error),
+                Ok(step) if step.halted=>return StopReason::Halted{pc},
+                Ok(_)=>{}
+            }
+            if let Some((index,cycle))=self.cpu.bus.hit.take(){
+                return StopReason::Watchpoint{index,cycle,pc};
+            }
+            if done(&self.cpu,code,sp){
+                return reason(self.cpu.program_counter);
+            }
+        }
+    }
+}
+
+fn step(pc:u16)->StopReason{
+    StopReason::Step{pc}
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::asm::assemble;
+
+    //サブルーチンを2回呼んでからループでXを数え、BRKで止まる
+    const PROGRAM:&str="
+        jsr sub         ; $0600
+        jsr sub         ; $0603
+        ldx #0          ; $0606
+    loop:
+        inx             ; $0608
+        cpx #5          ; $0609
+        bne loop        ; $060B
+        brk             ; $060D
+    sub:
+        lda $10         ; $060E
+        sta $0200       ; $0610
+        jsr inner       ; $0613
+        rts             ; $0616
+    inner:
+        rts             ; $0617
+";
+
+    fn debugger()->Debugger{
+        let mut debugger=Debugger::default();
+        debugger.cpu.load(assemble(PROGRAM).unwrap()).unwrap();
+        debugger
+    }
+
+    #[test]
+    fn test_breakpoint_stops_before_instruction() {
+        let mut debugger=debugger();
+        debugger.breakpoints.push(Breakpoint::new(0x0606));
+        assert_eq!(debugger.resume(),StopReason::Breakpoint{index:0,pc:0x0606});
+        assert_eq!(debugger.cpu.register_x,0);
+        //止まったところから再開するときは同じブレークポイントで止まらない
+        assert_eq!(debugger.resume(),StopReason::Halted{pc:0x060D});
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut debugger=debugger();
+        debugger.breakpoints.push(Breakpoint{addr:0x0609,condition:Some("x==3".parse().unwrap())});
+        assert_eq!(debugger.resume(),StopReason::Breakpoint{index:0,pc:0x0609});
+        assert_eq!(debugger.cpu.register_x,3);
+    }
+
+    #[test]
+    fn test_condition_from_str() {
+        assert_eq!(
+            "PC != $0600".parse(),
+            Ok(Condition{register:Register::PC,comparison:Comparison::Ne,value:0x0600})
+        );
+        assert_eq!("a>=0x10".parse::<Condition>().unwrap().to_string(),"a>=$10");
+        assert_eq!("sp<16".parse::<Condition>().unwrap().value,16);
+        assert!("q==1".parse::<Condition>().is_err());
+        assert!("a".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger=debugger();
+        debugger.watchpoints().push(Watchpoint{start:0x0200,end:0x02FF,kind:WatchKind::Read});
+        debugger.watchpoints().push(Watchpoint{start:0x01F0,end:0x0200,kind:WatchKind::Write});
+        //JSRが戻りアドレスをスタックに積む
+        let reason=debugger.resume();
+        assert_eq!(
+            reason,
+            StopReason::Watchpoint{index:1,cycle:BusCycle{addr:0x01FD,value:0x06,access:BusAccess::Write},pc:0x0600}
+        );
+        debugger.watchpoints().remove(1);
+        debugger.watchpoints().push(Watchpoint{start:0x0010,end:0x0010,kind:WatchKind::Access});
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Watchpoint{index:1,cycle:BusCycle{addr:0x0010,value:0x00,access:BusAccess::Read},pc:0x060E}
+        );
+        //STAは読まないのでReadのウォッチポイントには引っかからない
+        assert_eq!(
+            debugger.resume(),
+            StopReason::Watchpoint{index:1,cycle:BusCycle{addr:0x0010,value:0x00,access:BusAccess::Read},pc:0x060E}
+        );
+        //デバッガから覗いても引っかからない
+        debugger.cpu.mem_read(0x0200);
+        debugger.cpu.bus.armed=true;
+        debugger.cpu.peek(0x0010);
+        debugger.cpu.bus.armed=false;
+        assert_eq!(debugger.cpu.bus.hit,None);
+    }
+
+    #[test]
+    fn test_step_into_over_and_out() {
+        let mut debugger=debugger();
+        assert_eq!(debugger.step_into(),StopReason::Step{pc:0x060E});
+        assert_eq!(debugger.step_into(),StopReason::Step{pc:0x0610});
+        assert_eq!(debugger.step_into(),StopReason::Step{pc:0x0613});
+        let backtrace=debugger.cpu.backtrace();
+        assert_eq!(backtrace.len(),1);
+        assert_eq!((backtrace[0].caller,backtrace[0].target),(0x0600,0x060E));
+        //内側のJSRをまたいでも、呼び出し元まで戻る
+        assert_eq!(debugger.step_out(),StopReason::Step{pc:0x0603});
+        assert!(debugger.cpu.backtrace().is_empty());
+        assert_eq!(debugger.step_over(),StopReason::Step{pc:0x0606});
+        assert_eq!(debugger.step_over(),StopReason::Step{pc:0x0608});
+    }
+
+    #[test]
+    fn test_step_over_stops_at_breakpoint_inside() {
+        let mut debugger=debugger();
+        debugger.breakpoints.push(Breakpoint::new(0x0617));
+        assert_eq!(debugger.step_over(),StopReason::Breakpoint{index:0,pc:0x0617});
+        assert_eq!(debugger.step_out(),StopReason::Step{pc:0x0616});
+    }
+
+    #[test]
+    fn test_run_until_and_limit() {
+        let mut debugger=debugger();
+        assert_eq!(debugger.run_until(0x060B),StopReason::Reached{pc:0x060B});
+        debugger.step_limit=Some(4);
+        assert_eq!(debugger.resume(),StopReason::Limit{pc:0x0608});
+        debugger.step_limit=None;
+        debugger.cpu.mem_write(0x060D,0x02);//BRKをJAMに書き換える
+        assert_eq!(debugger.resume(),StopReason::Error(CpuError::Jammed{pc:0x060D}));
+    }
+
+    #[test]
+    fn test_interruptible_step_keeps_its_target() {
+        //XとYで256*256回まわってから戻る
+        let program=assemble("
+            jsr slow    ; $0600
+            brk         ; $0603
+        slow:
+            ldy #0
+            ldx #0
+        loop:
+            dex
+            bne loop
+            dey
+            bne loop
+            rts
+        ").unwrap();
+        let mut debugger=Debugger::default();
+        debugger.cpu.load(program.clone()).unwrap();
+        let mut polls=0;
+        assert_eq!(debugger.step_over_interruptible(&mut ||{polls+=1;false}),StopReason::Step{pc:0x0603});
+        assert!(polls>1,"{}",polls);
+
+        let mut debugger=Debugger::default();
+        debugger.cpu.load(program).unwrap();
+        let mut polls=0;
+        let reason=debugger.step_over_interruptible(&mut ||{polls+=1;polls==2});
+        assert_eq!(reason,StopReason::Interrupted{pc:debugger.cpu.program_counter});
+        assert_eq!(debugger.cpu.backtrace().len(),1);
+        assert_eq!(debugger.step_out_interruptible(&mut ||false),StopReason::Step{pc:0x0603});
+    }
+}
@@ -0,0 +1,315 @@
+use crate::cpu::{AddressingMode, CpuVariant, Mem};
+use crate::opcodes;
+use crate::symbols::SymbolTable;
+use std::fmt;
+
+/// 逆アセンブルした1命令
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Instruction{
+    pub addr:u16,
+    pub bytes:Vec<u8>,
+    pub mnemonic:&'static str,
+    pub operand:String,//"#$05","($10),Y"など。オペランドのない命令は空
+    pub mode:Option<AddressingMode>,//命令表にないバイトはNone
+    pub unofficial:bool,
+}
+
+impl Instruction{
+    /// 次の命令のアドレス
+    pub fn next_addr(&self)->u16{
+        self.addr.wrapping_add(self.bytes.len() as u16)
+    }
+
+    /// 分岐・ジャンプ先のアドレス(JMP間接やRTSなど、バイト列だけで決まらないものはNone)
+    pub fn target(&self)->Option<u16>{
+        match self.mode{
+            Some(AddressingMode::Relative)=>Some(relative_target(self.addr,self.bytes[1])),
+            Some(AddressingMode::ZeroPage_Relative)=>Some(relative_target(self.addr.wrapping_add(1),self.bytes[2])),
+            Some(AddressingMode::Absolute) if self.mnemonic=="JMP"||self.mnemonic=="JSR"=>{
+                Some((self.bytes[2] as u16)<<8|(self.bytes[1] as u16))
+            }
+            _=>None,
+        }
+    }
+
+    /// "A9 05"のような16進ダンプ
+    pub fn hex(&self)->String{
+        self.bytes.iter().map(|b|format!("{:02X}",b)).collect::<Vec<String>>().join(" ")
+    }
+
+    /// オペランドのアドレスをラベルに置き換えた"JSR init_snake"。即値と".byte"はそのまま
+    pub fn with_symbols(&self,symbols:&SymbolTable)->String{
+        if self.operand.is_empty(){
+            return self.mnemonic.to_string();
+        }
+        format!("{} {}",self.mnemonic,self.operand_with_symbols(symbols))
+    }
+
+    /// with_symbolsのオペランドだけ
+    pub fn operand_with_symbols(&self,symbols:&SymbolTable)->String{
+        if self.mode.is_none(){
+            return self.operand.clone();
+        }
+        let mut operand=String::new();
+        let mut rest=self.operand.as_str();
+        while let Some(start)=rest.find('$'){
+            let (before,token)=rest.split_at(start);
+            operand.push_str(before);
+            let len=1+token[1..].find(|c:char|!c.is_ascii_hexdigit()).unwrap_or(token.len()-1);
+            let name=u16::from_str_radix(&token[1..len],16)
+                .ok()
+                .filter(|_|!before.ends_with('#'))
+                .and_then(|addr|symbols.name_at(addr));
+            operand.push_str(name.unwrap_or(&token[..len]));
+            rest=&token[len..];
+        }
+        operand.push_str(rest);
+        operand
+    }
+}
+
+impl fmt::Display for Instruction{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        if self.operand.is_empty(){
+            write!(f,"{}",self.mnemonic)
+        }else{
+            write!(f,"{} {}",self.mnemonic,self.operand)
+        }
+    }
+}
+
+fn relative_target(addr:u16,offset:u8)->u16{
+    addr.wrapping_add(2).wrapping_add((offset as i8) as u16)
+}
+
+/// addrにあるbytes[0]から始まる1命令をデコードする。bytesが空ならNone
+/// 命令表にないバイトや途中で切れた命令は".byte"として1byteだけ消費する
+pub fn decode(bytes:&[u8],addr:u16)->Option<Instruction>{
+    decode_for(CpuVariant::Ricoh2A03,bytes,addr)
+}
+
+/// decodeと同じ。variantの命令表を使う
+pub fn decode_for(variant:CpuVariant,bytes:&[u8],addr:u16)->Option<Instruction>{
+    if bytes.is_empty(){
+        return None;
+    }
+    Some(decode_bytes(variant,bytes,addr))
+}
+
+//bytesは空でないこと
+fn decode_bytes(variant:CpuVariant,bytes:&[u8],addr:u16)->Instruction{
+    let code=bytes[0];
+    let ops=match opcodes::table(variant)[code as usize]{
+        Some(ops) if bytes.len()>=ops.len as usize=>ops,
+        _=>{
+            return Instruction{
+                addr,
+                bytes:vec![code],
+                mnemonic:".byte",
+                operand:format!("${:02X}",code),
+                mode:None,
+                unofficial:false,
+            };
+        }
+    };
+
+    let bytes=bytes[..ops.len as usize].to_vec();
+    let operand=match ops.mode{
+        AddressingMode::Immediate=>format!("#${:02X}",bytes[1]),
+        AddressingMode::ZeroPage=>format!("${:02X}",bytes[1]),
+        AddressingMode::ZeroPage_X=>format!("${:02X},X",bytes[1]),
+        AddressingMode::ZeroPage_Y=>format!("${:02X},Y",bytes[1]),
+        AddressingMode::Absolute=>format!("${:02X}{:02X}",bytes[2],bytes[1]),
+        AddressingMode::Absolute_X=>format!("${:02X}{:02X},X",bytes[2],bytes[1]),
+        AddressingMode::Absolute_Y=>format!("${:02X}{:02X},Y",bytes[2],bytes[1]),
+        AddressingMode::Indirect=>format!("(${:02X}{:02X})",bytes[2],bytes[1]),
+        AddressingMode::Indirect_X=>format!("(${:02X},X)",bytes[1]),
+        AddressingMode::Indirect_Y=>format!("(${:02X}),Y",bytes[1]),
+        AddressingMode::Relative=>format!("${:04X}",relative_target(addr,bytes[1])),
+        AddressingMode::ZeroPage_Indirect=>format!("(${:02X})",bytes[1]),
+        AddressingMode::Absolute_X_Indirect=>format!("(${:02X}{:02X},X)",bytes[2],bytes[1]),
+        AddressingMode::ZeroPage_Relative=>format!("${:02X},${:04X}",bytes[1],relative_target(addr.wrapping_add(1),bytes[2])),
+        AddressingMode::Accumulator=>"A".to_string(),
+        AddressingMode::NoneAddressing=>String::new(),
+    };
+
+    Instruction{
+        addr,
+        bytes,
+        mnemonic:ops.mnemonic,
+        operand,
+        mode:Some(ops.mode),
+        unofficial:ops.unofficial,
+    }
+}
+
+/// メモリ上のaddrにある1命令をデコードする
+pub fn decode_at<M:Mem>(mem:&mut M,addr:u16)->Instruction{
+    decode_at_for(CpuVariant::Ricoh2A03,mem,addr)
+}
+
+/// decode_atと同じ。variantの命令表を使う
+pub fn decode_at_for<M:Mem>(variant:CpuVariant,mem:&mut M,addr:u16)->Instruction{
+    let bytes:Vec<u8>=(0..3).map(|i|mem.peek(addr.wrapping_add(i))).collect();
+    decode_bytes(variant,&bytes,addr)
+}
+
+/// originに置かれたバイト列をすべて逆アセンブルする
+pub fn disassemble(bytes:&[u8],origin:u16)->Vec<Instruction>{
+    disassemble_for(CpuVariant::Ricoh2A03,bytes,origin)
+}
+
+/// disassembleと同じ。variantの命令表を使う
+pub fn disassemble_for(variant:CpuVariant,bytes:&[u8],origin:u16)->Vec<Instruction>{
+    let mut result=vec![];
+    let mut offset=0;
+    while let Some(instruction)=decode_for(variant,&bytes[offset..],origin.wrapping_add(offset as u16)){
+        offset+=instruction.bytes.len();
+        result.push(instruction);
+    }
+    result
+}
+
+/// メモリ上のstartからend(含まない)までを逆アセンブルする
+pub fn disassemble_range<M:Mem>(mem:&mut M,start:u16,end:u16)->Vec<Instruction>{
+    let mut result=vec![];
+    let mut addr=start as u32;
+    while addr<end as u32{
+        let instruction=decode_at(mem,addr as u16);
+        addr+=instruction.bytes.len() as u32;
+        result.push(instruction);
+    }
+    result
+}
+
+/// "0600  A9 05     LDA #$05"の形式の1行
+pub fn format_line(instruction:&Instruction)->String{
+    format!("{:04X}  {:8}  {}",instruction.addr,instruction.hex(),instruction)
+}
+
+/// format_lineと同じ。オペランドのアドレスをラベルにする
+pub fn format_line_with_symbols(instruction:&Instruction,symbols:&SymbolTable)->String{
+    format!("{:04X}  {:8}  {}",instruction.addr,instruction.hex(),instruction.with_symbols(symbols))
+}
+
+/// 逆アセンブル結果をリスティングにする
+pub fn listing(instructions:&[Instruction])->String{
+    let mut text=String::new();
+    for instruction in instructions{
+        text.push_str(&format_line(instruction));
+        text.push('\n');
+    }
+    text
+}
+
+/// ラベルのある命令の前に"name:"の行を入れたリスティング
+pub fn listing_with_symbols(instructions:&[Instruction],symbols:&SymbolTable)->String{
+    let mut text=String::new();
+    for instruction in instructions{
+        if let Some(name)=symbols.name_at(instruction.addr){
+            text.push_str(name);
+            text.push_str(":\n");
+        }
+        text.push_str(&format_line_with_symbols(instruction,symbols));
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::bus::FlatMemory;
+
+    #[test]
+    fn test_decode_all_addressing_modes(){
+        let cases:Vec<(Vec<u8>,&str)>=vec![
+            (vec![0xa9,0x05],"LDA #$05"),
+            (vec![0xa5,0x10],"LDA $10"),
+            (vec![0xb5,0x10],"LDA $10,X"),
+            (vec![0xb6,0x10],"LDX $10,Y"),
+            (vec![0xad,0x34,0x12],"LDA $1234"),
+            (vec![0xbd,0x34,0x12],"LDA $1234,X"),
+            (vec![0xb9,0x34,0x12],"LDA $1234,Y"),
+            (vec![0x6c,0xfc,0xff],"JMP ($FFFC)"),
+            (vec![0xa1,0x10],"LDA ($10,X)"),
+            (vec![0xb1,0x10],"LDA ($10),Y"),
+            (vec![0x0a],"ASL A"),
+            (vec![0xea],"NOP"),
+            (vec![0xa7,0x10],"LAX $10"),
+        ];
+        for (bytes,text) in cases{
+            assert_eq!(decode(&bytes,0x0600).unwrap().to_string(),text);
+        }
+    }
+
+    #[test]
+    fn test_decode_relative_target(){
+        let back=decode(&[0xd0,0xfb],0x0735).unwrap();
+        assert_eq!(back.to_string(),"BNE $0732");
+        assert_eq!(back.target(),Some(0x0732));
+        let forward=decode(&[0xf0,0x0d],0x0653).unwrap();
+        assert_eq!(forward.to_string(),"BEQ $0662");
+    }
+
+    #[test]
+    fn test_decode_truncated_instruction(){
+        let instruction=decode(&[0xad,0x34],0x0600).unwrap();
+        assert_eq!(instruction.to_string(),".byte $AD");
+        assert_eq!(instruction.bytes.len(),1);
+        assert_eq!(decode(&[],0x0600),None);
+    }
+
+    #[test]
+    fn test_disassemble_listing(){
+        //JSR $0606; JSR $0638; BRK
+        let instructions=disassemble(&[0x20,0x06,0x06,0x20,0x38,0x06,0x00],0x0600);
+        assert_eq!(instructions.len(),3);
+        assert_eq!(instructions[0].target(),Some(0x0606));
+        assert_eq!(
+            listing(&instructions),
+            "0600  20 06 06  JSR $0606\n0603  20 38 06  JSR $0638\n0606  00        BRK\n"
+        );
+    }
+
+    #[test]
+    fn test_listing_with_symbols(){
+        let symbols=SymbolTable::parse("init_snake = $0605\nscreen = $0200\nsysRandom = $FE\nloop = $0610\nlow = $AD").unwrap();
+        //JSR $0605; LDA #$FE; STA $0200,X; LDA ($FE),Y; 途中で切れたLDA abs
+        let bytes=[0x20,0x05,0x06,0xa9,0xfe,0x9d,0x00,0x02,0xb1,0xfe,0xad];
+        let instructions=disassemble(&bytes,0x0600);
+        assert_eq!(
+            listing_with_symbols(&instructions,&symbols),
+            "0600  20 05 06  JSR init_snake\n0603  A9 FE     LDA #$FE\ninit_snake:\n0605  9D 00 02  STA screen,X\n0608  B1 FE     LDA (sysRandom),Y\n060A  AD        .byte $AD\n"
+        );
+        let bbr=decode_for(CpuVariant::Wdc65C02,&[0x0f,0xfe,0x0d],0x0600).unwrap();
+        assert_eq!(bbr.with_symbols(&symbols),"BBR0 sysRandom,loop");
+    }
+
+    #[test]
+    fn test_disassemble_range_from_memory(){
+        let mut mem=FlatMemory::new();
+        mem.mem_write(0xFFFE,0xa9);
+        mem.mem_write(0xFFFF,0x01);
+        let instructions=disassemble_range(&mut mem,0xFFFE,0xFFFF);
+        assert_eq!(instructions.len(),1);
+        assert_eq!(instructions[0].to_string(),"LDA #$01");
+    }
+
+    #[test]
+    fn test_decode_65c02(){
+        let cases:Vec<(Vec<u8>,&str)>=vec![
+            (vec![0xb2,0x10],"LDA ($10)"),
+            (vec![0x7c,0x00,0x08],"JMP ($0800,X)"),
+            (vec![0x0f,0x12,0x03],"BBR0 $12,$0606"),
+            (vec![0x80,0xfe],"BRA $0600"),
+            (vec![0x1a],"INC A"),
+        ];
+        for (bytes,text) in cases{
+            assert_eq!(decode_for(CpuVariant::Wdc65C02,&bytes,0x0600).unwrap().to_string(),text);
+        }
+        assert_eq!(decode_for(CpuVariant::Wdc65C02,&[0x0f,0x12,0x03],0x0600).unwrap().target(),Some(0x0606));
+        //NMOSでは非公式命令
+        assert!(decode(&[0x1a],0x0600).unwrap().unofficial);
+    }
+}
@@ -0,0 +1,511 @@
+// GDBのリモートシリアルプロトコル(RSP)のサーバ
+//
+// localhostのTCPで1つの接続を受け、gdbやlldbの"target remote"からDebuggerを操作できるようにする。
+// レジスタはa,x,y,p,sp(各8bit)とpc(16bit、リトルエンディアン)の順で、target.xmlでも同じ並びを返す
+use crate::cpu::{CpuError, CpuFlags, Mem};
+use crate::debugger::{Breakpoint, Debugger, StopReason, WatchKind, Watchpoint};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const TARGET_XML:&str=r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.6502.core">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8" regnum="1"/>
+    <reg name="y" bitsize="8" regnum="2"/>
+    <reg name="p" bitsize="8" regnum="3"/>
+    <reg name="sp" bitsize="8" regnum="4"/>
+    <reg name="pc" bitsize="16" regnum="5" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+//停止理由のシグナル
+const SIGINT:u8=2;
+const SIGILL:u8=4;
+const SIGTRAP:u8=5;
+
+/// 受け取ったパケット
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Packet{
+    Command(String),
+    Interrupt,//Ctrl-C(0x03)
+    Corrupt,//チェックサムが合わない。'-'を返して送り直してもらう
+}
+
+/// パケットを処理したあとにすること
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Action{
+    Reply(String),
+    ReplyAndClose(String),//detach
+    Close,//kill
+}
+
+fn checksum(data:&str)->u8{
+    data.bytes().fold(0u8,|sum,byte|sum.wrapping_add(byte))
+}
+
+/// "$data#xx"の形にする
+pub fn frame(data:&str)->String{
+    format!("${}#{:02x}",data,checksum(data))
+}
+
+/// 次のパケットを読む。ACK('+','-')は読み飛ばす。接続が閉じればNone
+pub fn read_packet<R:Read>(reader:&mut R)->io::Result<Option<Packet>>{
+    let mut byte=[0u8];
+    loop{
+        if reader.read(&mut byte)?==0{
+            return Ok(None);
+        }
+        match byte[0]{
+            0x03=>return Ok(Some(Packet::Interrupt)),
+            b'$'=>break,
+            _=>{}
+        }
+    }
+    let mut data=vec![];
+    loop{
+        if reader.read(&mut byte)?==0{
+            return Ok(None);
+        }
+        if byte[0]==b'#'{
+            break;
+        }
+        data.push(byte[0]);
+    }
+    let mut sum=[0u8;2];
+    reader.read_exact(&mut sum)?;
+    let data=String::from_utf8_lossy(&data).into_owned();
+    let expected=u8::from_str_radix(&String::from_utf8_lossy(&sum),16).ok();
+    if expected!=Some(checksum(&data)){
+        return Ok(Some(Packet::Corrupt));
+    }
+    Ok(Some(Packet::Command(data)))
+}
+
+fn hex_bytes(bytes:&[u8])->String{
+    bytes.iter().map(|byte|format!("{:02x}",byte)).collect()
+}
+
+fn parse_hex_bytes(text:&str)->Option<Vec<u8>>{
+    if !text.len().is_multiple_of(2){
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i|u8::from_str_radix(text.get(i..i+2)?,16).ok()).collect()
+}
+
+fn parse_hex(text:&str)->Option<u32>{
+    u32::from_str_radix(text,16).ok()
+}
+
+pub struct GdbStub<M:Mem>{
+    pub debugger:Debugger<M>,
+    no_ack:bool,
+}
+
+impl<M:Mem> GdbStub<M>{
+    pub fn new(debugger:Debugger<M>)->Self{
+        GdbStub{debugger,no_ack:false}
+    }
+
+    /// 1つの接続を受けて、切れるまで応答する
+    pub fn serve(&mut self,listener:&TcpListener)->io::Result<()>{
+        let (stream,_)=listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve_connection(stream)
+    }
+
+    fn serve_connection(&mut self,mut stream:TcpStream)->io::Result<()>{
+        self.no_ack=false;
+        while let Some(packet)=read_packet(&mut stream)?{
+            let command=match packet{
+                Packet::Command(command)=>command,
+                Packet::Interrupt=>continue,//止まっているときのCtrl-Cは無視する
+                Packet::Corrupt=>{
+                    if !self.no_ack{
+                        stream.write_all(b"-")?;
+                    }
+                    continue;
+                }
+            };
+            if !self.no_ack{
+                stream.write_all(b"+")?;
+            }
+            let action={
+                let mut interrupted=||interrupt_requested(&mut stream);
+                self.handle(&command,&mut interrupted)
+            };
+            match action{
+                Action::Reply(reply)=>stream.write_all(frame(&reply).as_bytes())?,
+                Action::ReplyAndClose(reply)=>{
+                    stream.write_all(frame(&reply).as_bytes())?;
+                    return Ok(());
+                }
+                Action::Close=>return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// 1つのパケットを処理する。continueの途中でinterrupted()がtrueを返すと止める
+    pub fn handle(&mut self,command:&str,interrupted:&mut dyn FnMut()->bool)->Action{
+        let reply=match command.as_bytes().first(){
+            Some(b'?')=>stop_reply(SIGTRAP),
+            Some(b'g')=>self.read_registers(),
+            Some(b'G')=>self.write_registers(&command[1..]),
+            Some(b'p')=>self.read_register(&command[1..]),
+            Some(b'P')=>self.write_register(&command[1..]),
+            Some(b'm')=>self.read_memory(&command[1..]),
+            Some(b'M')=>self.write_memory(&command[1..]),
+            Some(b'Z')=>self.set_point(&command[1..],true),
+            Some(b'z')=>self.set_point(&command[1..],false),
+            Some(b's')=>{
+                self.jump(&command[1..]);
+                let reason=self.debugger.step_into();
+                self.stop_reason(&reason)
+            }
+            Some(b'c')=>{
+                self.jump(&command[1..]);
+                let reason=self.debugger.resume_interruptible(interrupted);
+                self.stop_reason(&reason)
+            }
+            Some(b'H')=>"OK".to_string(),
+            Some(b'k')=>return Action::Close,
+            Some(b'D')=>return Action::ReplyAndClose("OK".to_string()),
+            Some(b'q')|Some(b'Q')=>self.query(command),
+            _=>String::new(),//知らないパケットには空で答える
+        };
+        Action::Reply(reply)
+    }
+
+    fn query(&mut self,command:&str)->String{
+        if command.starts_with("qSupported"){
+            return "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(annex)=command.strip_prefix("qXfer:features:read:target.xml:"){
+            return self.read_target_xml(annex);
+        }
+        match command{
+            "QStartNoAckMode"=>{
+                self.no_ack=true;
+                "OK".to_string()
+            }
+            "qAttached"=>"1".to_string(),
+            "qC"=>"QC1".to_string(),
+            "qfThreadInfo"=>"m1".to_string(),
+            "qsThreadInfo"=>"l".to_string(),
+            _=>String::new(),
+        }
+    }
+
+    //"offset,length"で指定された範囲を返す。最後の断片には'l'、続きがあれば'm'を付ける
+    fn read_target_xml(&self,annex:&str)->String{
+        let range=annex.split_once(',').and_then(|(offset,length)|Some((parse_hex(offset)?,parse_hex(length)?)));
+        let (offset,length)=match range{
+            Some((offset,length))=>(offset as usize,length as usize),
+            None=>return "E01".to_string(),
+        };
+        let start=offset.min(TARGET_XML.len());
+        let end=(offset+length).min(TARGET_XML.len());
+        let prefix=if end==TARGET_XML.len(){"l"}else{"m"};
+        format!("{}{}",prefix,&TARGET_XML[start..end])
+    }
+
+    fn registers(&self)->[u8;7]{
+        let r=self.debugger.cpu.registers();
+        [r.a,r.x,r.y,r.p.bits(),r.sp,r.pc as u8,(r.pc>>8) as u8]
+    }
+
+    fn set_registers(&mut self,bytes:&[u8;7]){
+        let mut r=self.debugger.cpu.registers();
+        r.a=bytes[0];
+        r.x=bytes[1];
+        r.y=bytes[2];
+        r.p=CpuFlags::from_bits_truncate(bytes[3]);
+        r.sp=bytes[4];
+        r.pc=(bytes[6] as u16)<<8|bytes[5] as u16;
+        self.debugger.cpu.set_registers(r);
+    }
+
+    fn read_registers(&self)->String{
+        hex_bytes(&self.registers())
+    }
+
+    fn write_registers(&mut self,data:&str)->String{
+        match parse_hex_bytes(data).and_then(|bytes|<[u8;7]>::try_from(bytes).ok()){
+            Some(bytes)=>{
+                self.set_registers(&bytes);
+                "OK".to_string()
+            }
+            None=>"E01".to_string(),
+        }
+    }
+
+    fn read_register(&self,data:&str)->String{
+        let bytes=self.registers();
+        match parse_hex(data){
+            Some(n @ 0..=4)=>hex_bytes(&bytes[n as usize..n as usize+1]),
+            Some(5)=>hex_bytes(&bytes[5..7]),
+            _=>"E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self,data:&str)->String{
+        let parsed=data.split_once('=').and_then(|(n,value)|Some((parse_hex(n)?,parse_hex_bytes(value)?)));
+        let mut bytes=self.registers();
+        match parsed{
+            Some((n @ 0..=4,value)) if value.len()==1=>bytes[n as usize]=value[0],
+            Some((5,value)) if value.len()==2=>bytes[5..7].copy_from_slice(&value),
+            _=>return "E01".to_string(),
+        }
+        self.set_registers(&bytes);
+        "OK".to_string()
+    }
+
+    //アドレス空間は64KBなので、はみ出す範囲はエラーにする
+    fn memory_range(data:&str)->Option<(u16,usize)>{
+        let (addr,length)=data.split_once(',')?;
+        let (addr,length)=(parse_hex(addr)?,parse_hex(length)?);
+        if addr.checked_add(length)?>0x10000{
+            return None;
+        }
+        Some((addr as u16,length as usize))
+    }
+
+    fn read_memory(&mut self,data:&str)->String{
+        let (addr,length)=match Self::memory_range(data){
+            Some(range)=>range,
+            None=>return "E01".to_string(),
+        };
+        let bytes:Vec<u8>=(0..length).map(|i|self.debugger.cpu.peek(addr.wrapping_add(i as u16))).collect();
+        hex_bytes(&bytes)
+    }
+
+    fn write_memory(&mut self,data:&str)->String{
+        let parsed=data
+            .split_once(':')
+            .and_then(|(range,bytes)|Some((Self::memory_range(range)?,parse_hex_bytes(bytes)?)));
+        match parsed{
+            Some(((addr,length),bytes)) if bytes.len()==length=>{
+                for (i,byte) in bytes.iter().enumerate(){
+                    self.debugger.cpu.mem_write(addr.wrapping_add(i as u16),*byte);
+                }
+                "OK".to_string()
+            }
+            _=>"E01".to_string(),
+        }
+    }
+
+    //Z0(ソフトウェア),Z1(ハードウェア)はブレークポイント、Z2~Z4は書き込み/読み込み/アクセスのウォッチポイント
+    fn set_point(&mut self,data:&str,insert:bool)->String{
+        let (kind,addr,length)=match parse_point(data){
+            Some((kind,addr,length)) if addr<=0xFFFF=>(kind,addr as u16,length.max(1) as u16),
+            _=>return "E01".to_string(),
+        };
+        let watch=match kind{
+            "0"|"1"=>None,
+            "2"=>Some(WatchKind::Write),
+            "3"=>Some(WatchKind::Read),
+            "4"=>Some(WatchKind::Access),
+            _=>return String::new(),
+        };
+        match (watch,insert){
+            (None,true)=>self.debugger.breakpoints.push(Breakpoint::new(addr)),
+            (None,false)=>self.debugger.breakpoints.retain(|breakpoint|breakpoint.addr!=addr),
+            (Some(kind),true)=>{
+                let end=addr.saturating_add(length-1);
+                self.debugger.watchpoints().push(Watchpoint{start:addr,end,kind});
+            }
+            (Some(kind),false)=>{
+                self.debugger.watchpoints().retain(|watchpoint|watchpoint.start!=addr||watchpoint.kind!=kind);
+            }
+        }
+        "OK".to_string()
+    }
+
+    //"c addr"や"s addr"のようにアドレスが付いていれば、そこから再開する
+    fn jump(&mut self,addr:&str){
+        if let Some(addr)=parse_hex(addr).filter(|addr|*addr<=0xFFFF){
+            self.debugger.cpu.program_counter=addr as u16;
+        }
+    }
+
+    fn stop_reason(&self,reason:&StopReason)->String{
+        match reason{
+            StopReason::Watchpoint{index,cycle,..}=>{
+                let name=match self.debugger.cpu.bus.watchpoints.get(*index).map(|watchpoint|watchpoint.kind){
+                    Some(WatchKind::Read)=>"rwatch",
+                    Some(WatchKind::Access)=>"awatch",
+                    _=>"watch",
+                };
+                format!("T{:02x}{}:{:04x};",SIGTRAP,name,cycle.addr)
+            }
+            StopReason::Interrupted{..}=>stop_reply(SIGINT),
+            StopReason::Error(CpuError::Jammed{..})|StopReason::Error(CpuError::UnknownOpcode{..})=>stop_reply(SIGILL),
+            _=>stop_reply(SIGTRAP),
+        }
+    }
+}
+
+//"種類,アドレス,長さ"
+fn parse_point(data:&str)->Option<(&str,u32,u32)>{
+    let mut fields=data.split(',');
+    Some((fields.next()?,parse_hex(fields.next()?)?,parse_hex(fields.next()?)?))
+}
+
+fn stop_reply(signal:u8)->String{
+    format!("S{:02x}",signal)
+}
+
+//読めるデータがあるかだけを待たずに確かめる。Ctrl-C以外のバイトは捨てる
+fn interrupt_requested(stream:&mut TcpStream)->bool{
+    if stream.set_nonblocking(true).is_err(){
+        return false;
+    }
+    let mut byte=[0u8];
+    let interrupted=matches!(stream.read(&mut byte),Ok(1) if byte[0]==0x03);
+    let _=stream.set_nonblocking(false);
+    interrupted
+}
+
+/// 127.0.0.1:portで接続を待ち、1つのセッションが終わるまで応答する
+pub fn serve<M:Mem>(debugger:Debugger<M>,port:u16)->io::Result<()>{
+    let listener=TcpListener::bind(("127.0.0.1",port))?;
+    eprintln!("waiting for gdb on 127.0.0.1:{} (target remote :{})",port,port);
+    GdbStub::new(debugger).serve(&listener)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::asm::assemble;
+    use crate::bus::FlatMemory;
+    use std::thread;
+
+    //INX; CPX #3; BNE -5; BRK
+    fn stub()->GdbStub<FlatMemory>{
+        let mut debugger=Debugger::default();
+        debugger.cpu.load(assemble("loop:\n inx\n cpx #3\n bne loop\n brk\n").unwrap()).unwrap();
+        GdbStub::new(debugger)
+    }
+
+    fn reply(stub:&mut GdbStub<FlatMemory>,command:&str)->String{
+        match stub.handle(command,&mut ||false){
+            Action::Reply(reply)=>reply,
+            action=>panic!("unexpected {:?}",action),
+        }
+    }
+
+    #[test]
+    fn test_read_packet(){
+        let data=format!("+$g#67+{}\x03",frame("m0600,2"));
+        let mut input=data.as_bytes();
+        assert_eq!(read_packet(&mut input).unwrap(),Some(Packet::Command("g".to_string())));
+        assert_eq!(read_packet(&mut input).unwrap(),Some(Packet::Command("m0600,2".to_string())));
+        assert_eq!(read_packet(&mut input).unwrap(),Some(Packet::Interrupt));
+        assert_eq!(read_packet(&mut input).unwrap(),None);
+        let mut bad:&[u8]=b"$g#00$g#67";
+        assert_eq!(read_packet(&mut bad).unwrap(),Some(Packet::Corrupt));
+        assert_eq!(read_packet(&mut bad).unwrap(),Some(Packet::Command("g".to_string())));
+        assert_eq!(frame("OK"),"$OK#9a");
+    }
+
+    #[test]
+    fn test_registers(){
+        let mut stub=stub();
+        assert_eq!(reply(&mut stub,"g"),"00000020fd0006");
+        assert_eq!(reply(&mut stub,"P1=7f"),"OK");
+        assert_eq!(reply(&mut stub,"P5=0207"),"OK");
+        assert_eq!(reply(&mut stub,"p5"),"0207");
+        assert_eq!(stub.debugger.cpu.register_x,0x7f);
+        assert_eq!(stub.debugger.cpu.program_counter,0x0702);
+        assert_eq!(reply(&mut stub,"G0102032cfb0006"),"OK");
+        assert_eq!(reply(&mut stub,"g"),"0102032cfb0006");
+        assert_eq!(reply(&mut stub,"p6"),"E01");
+    }
+
+    #[test]
+    fn test_memory(){
+        let mut stub=stub();
+        assert_eq!(reply(&mut stub,"m0600,3"),"e8e003");
+        assert_eq!(reply(&mut stub,"M0200,2:abcd"),"OK");
+        assert_eq!(reply(&mut stub,"m0200,2"),"abcd");
+        assert_eq!(reply(&mut stub,"mffff,2"),"E01");
+        assert_eq!(reply(&mut stub,"m1,ffffffff"),"E01");
+        assert_eq!(reply(&mut stub,"M0200,2:ab"),"E01");
+    }
+
+    #[test]
+    fn test_breakpoints_step_and_continue(){
+        let mut stub=stub();
+        assert_eq!(reply(&mut stub,"s"),"S05");
+        assert_eq!(stub.debugger.cpu.program_counter,0x0601);
+        assert_eq!(reply(&mut stub,"Z0,603,1"),"OK");
+        assert_eq!(reply(&mut stub,"c"),"S05");
+        assert_eq!(stub.debugger.cpu.program_counter,0x0603);
+        assert_eq!(reply(&mut stub,"z0,603,1"),"OK");
+        assert_eq!(reply(&mut stub,"Z2,0200,1"),"OK");
+        assert_eq!(reply(&mut stub,"M0605,3:8d0002"),"OK");//BRKをSTA $0200に書き換える
+        assert_eq!(reply(&mut stub,"c"),"T05watch:0200;");
+        assert_eq!(stub.debugger.cpu.register_x,3);
+    }
+
+    #[test]
+    fn test_continue_can_be_interrupted(){
+        let mut stub=stub();
+        stub.debugger.cpu.mem_write(0x0604,0xfe);//BNE自身へ飛ぶ無限ループ
+        stub.debugger.cpu.register_x=0xff;
+        let mut polls=0;
+        let action=stub.handle("c",&mut ||{
+            polls+=1;
+            polls==3
+        });
+        assert_eq!(action,Action::Reply("S02".to_string()));
+        assert_eq!(stub.debugger.step_limit,None);
+    }
+
+    #[test]
+    fn test_queries(){
+        let mut stub=stub();
+        assert!(reply(&mut stub,"qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let xml=reply(&mut stub,"qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("name=\"pc\" bitsize=\"16\""));
+        assert!(reply(&mut stub,"qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+        assert_eq!(reply(&mut stub,"vMustReplyEmpty"),"");
+        assert_eq!(stub.handle("D",&mut ||false),Action::ReplyAndClose("OK".to_string()));
+        assert_eq!(stub.handle("k",&mut ||false),Action::Close);
+    }
+
+    #[test]
+    fn test_session_over_tcp(){
+        let listener=TcpListener::bind(("127.0.0.1",0)).unwrap();
+        let port=listener.local_addr().unwrap().port();
+        let server=thread::spawn(move ||{
+            let mut stub=stub();
+            stub.serve(&listener).unwrap();
+            stub.debugger.cpu.register_x
+        });
+        let mut client=TcpStream::connect(("127.0.0.1",port)).unwrap();
+        //壊れたパケットには'-'を返し、接続はそのまま
+        client.write_all(b"$?#00").unwrap();
+        let mut nak=[0u8];
+        client.read_exact(&mut nak).unwrap();
+        assert_eq!(&nak,b"-");
+        let mut exchange=|command:&str|->String{
+            client.write_all(frame(command).as_bytes()).unwrap();
+            let mut ack=[0u8];
+            client.read_exact(&mut ack).unwrap();
+            assert_eq!(&ack,b"+");
+            match read_packet(&mut client).unwrap(){
+                Some(Packet::Command(reply))=>reply,
+                packet=>panic!("unexpected {:?}",packet),
+            }
+        };
+        assert_eq!(exchange("?"),"S05");
+        assert_eq!(exchange("c"),"S05");//BRKで止まる
+        assert_eq!(exchange("D"),"OK");
+        assert_eq!(server.join().unwrap(),3);
+    }
+}
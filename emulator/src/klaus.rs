@@ -0,0 +1,241 @@
+// Klaus Dormannの6502_functional_test.bin/6502_decimal_test.binを実行する
+//
+// テストは失敗すると"BNE *"のような自分自身へ飛ぶ命令(トラップ)で止まり、
+// 最後まで通ると成功用のトラップ("JMP *")に入る。どちらのアドレスもlstファイルで確認できる
+// (functional testの標準のビルドでは$0000に置いて$0400から始め、成功は$3469)
+// decimal testは失敗してもトラップせず、最後のトラップに入る前にERROR($000B)を0以外にする
+use crate::bus::FlatMemory;
+use crate::cpu::{CpuError, CpuVariant, Mem, CPU};
+use crate::disasm;
+use std::collections::VecDeque;
+use std::fmt;
+
+//失敗したときに表示する直前の命令の数
+const HISTORY_LEN:usize=16;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct KlausConfig{
+    pub load_addr:u16,
+    pub start_pc:u16,
+    pub success_addr:u16,
+    pub error_addr:Option<u16>,//トラップに入ったとき0でなければ失敗にするバイト
+    pub max_instructions:u64,//トラップに入らないまま終わらないときの打ち切り
+    pub variant:CpuVariant,
+}
+
+impl KlausConfig{
+    /// 6502_functional_test.binの標準のビルド
+    pub fn functional_test()->Self{
+        KlausConfig{
+            load_addr:0x0000,
+            start_pc:0x0400,
+            success_addr:0x3469,
+            error_addr:None,
+            max_instructions:100_000_000,
+            //デシマルモードのテストも含まれているので2A03では通らない
+            variant:CpuVariant::Nmos6502,
+        }
+    }
+
+    /// 6502_decimal_test.binの標準のビルド(NMOSのA,Cだけを確かめる)
+    pub fn decimal_test()->Self{
+        KlausConfig{
+            load_addr:0x0000,
+            start_pc:0x0200,
+            success_addr:0x024B,
+            error_addr:Some(0x000B),
+            max_instructions:100_000_000,
+            variant:CpuVariant::Nmos6502,
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum KlausOutcome{
+    Passed{instructions:u64,cycles:usize},
+    Failed{trap:u16,context:String},//contextはトラップまでの命令とレジスタ
+    Timeout{pc:u16},
+    Error{error:CpuError,context:String},
+}
+
+impl KlausOutcome{
+    pub fn passed(&self)->bool{
+        matches!(self,KlausOutcome::Passed{..})
+    }
+}
+
+impl fmt::Display for KlausOutcome{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        match self{
+            KlausOutcome::Passed{instructions,cycles}=>{
+                write!(f,"passed ({} instructions, {} cycles)",instructions,cycles)
+            }
+            KlausOutcome::Failed{trap,context}=>write!(f,"failed: trapped at ${:04X}\n{}",trap,context),
+            KlausOutcome::Timeout{pc}=>write!(f,"gave up without reaching a trap (PC=${:04X})",pc),
+            KlausOutcome::Error{error,context}=>write!(f,"{}\n{}",error,context),
+        }
+    }
+}
+
+/// 64KBのバイナリをconfig.load_addrに置いて実行する
+pub fn run(binary:&[u8],config:&KlausConfig)->Result<KlausOutcome,CpuError>{
+    let mut cpu=CPU::with_bus(FlatMemory::new());
+    //BRKもテストされるので、本物の割り込みとして処理する
+    cpu.halt_on_brk=false;
+    cpu.variant=config.variant;
+    cpu.load_at(config.load_addr,binary)?;
+    cpu.program_counter=config.start_pc;
+    Ok(run_cpu(&mut cpu,config))
+}
+
+/// 読み込み済みのCPUをトラップに入るまで実行する
+pub fn run_cpu<M:Mem>(cpu:&mut CPU<M>,config:&KlausConfig)->KlausOutcome{
+    let mut history:VecDeque<u16>=VecDeque::with_capacity(HISTORY_LEN);
+    for instructions in 0..config.max_instructions{
+        let pc=cpu.program_counter;
+        if history.len()==HISTORY_LEN{
+            history.pop_front();
+        }
+        history.push_back(pc);
+
+        if let Err(error)=cpu.step(){
+            return KlausOutcome::Error{error,context:context(cpu,&history)};
+        }
+        if cpu.program_counter==pc{
+            let error=config.error_addr.map(|addr|(addr,cpu.peek(addr))).filter(|(_,value)|*value!=0);
+            if pc==config.success_addr&&error.is_none(){
+                return KlausOutcome::Passed{instructions:instructions+1,cycles:cpu.cycles};
+            }
+            let mut context=context(cpu,&history);
+            if let Some((addr,value))=error{
+                context.push_str(&format!("\nERROR(${:04X})=${:02X}",addr,value));
+            }
+            return KlausOutcome::Failed{trap:pc,context};
+        }
+    }
+    KlausOutcome::Timeout{pc:cpu.program_counter}
+}
+
+//直前に実行した命令の逆アセンブルとレジスタ
+fn context<M:Mem>(cpu:&mut CPU<M>,history:&VecDeque<u16>)->String{
+    let mut text=String::new();
+    for pc in history{
+        text.push_str(&disasm::format_line(&disasm::decode_at_for(cpu.variant,cpu,*pc)));
+        text.push('\n');
+    }
+    let r=cpu.registers();
+    text.push_str(&format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",r.a,r.x,r.y,r.p.bits(),r.sp));
+    text
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::asm::assemble_program;
+    use std::env;
+    use std::fs;
+
+    fn config(success_addr:u16)->KlausConfig{
+        KlausConfig{
+            load_addr:0x0600,
+            start_pc:0x0600,
+            success_addr,
+            error_addr:None,
+            max_instructions:1000,
+            variant:CpuVariant::Nmos6502,
+        }
+    }
+
+    #[test]
+    fn test_success_trap(){
+        let program=assemble_program("
+            ldx #3
+        loop:
+            dex
+            bne loop
+        success:
+            jmp success
+        ").unwrap();
+        let outcome=run(&program.bytes,&config(program.labels["success"])).unwrap();
+        //LDX(2)+DEX(2)*3+BNE(3,3,2)+JMP(3)
+        assert_eq!(outcome,KlausOutcome::Passed{instructions:8,cycles:19});
+    }
+
+    #[test]
+    fn test_failure_trap_shows_context(){
+        let program=assemble_program("
+            lda #1
+            cmp #2
+        fail:
+            bne fail
+        success:
+            jmp success
+        ").unwrap();
+        let outcome=run(&program.bytes,&config(program.labels["success"])).unwrap();
+        match outcome{
+            KlausOutcome::Failed{trap,context}=>{
+                assert_eq!(trap,0x0604);
+                assert_eq!(
+                    context,
+                    "0600  A9 01     LDA #$01\n0602  C9 02     CMP #$02\n0604  D0 FE     BNE $0604\nA:01 X:00 Y:00 P:A0 SP:FD"
+                );
+            }
+            other=>panic!("{:?}",other),
+        }
+    }
+
+    #[test]
+    fn test_error_byte_is_checked_at_trap(){
+        let source="
+            lda #VALUE
+            sta $0B
+        done:
+            jmp done
+        ";
+        let program=assemble_program(&source.replace("VALUE","0")).unwrap();
+        let config=KlausConfig{error_addr:Some(0x000B),..config(program.labels["done"])};
+        assert!(run(&program.bytes,&config).unwrap().passed());
+
+        let program=assemble_program(&source.replace("VALUE","1")).unwrap();
+        match run(&program.bytes,&config).unwrap(){
+            KlausOutcome::Failed{trap,context}=>{
+                assert_eq!(trap,0x0604);
+                assert!(context.ends_with("\nERROR($000B)=$01"),"{}",context);
+            }
+            other=>panic!("{:?}",other),
+        }
+    }
+
+    #[test]
+    fn test_timeout_and_error(){
+        let program=assemble_program("loop: nop\njmp loop").unwrap();
+        assert_eq!(run(&program.bytes,&config(0)).unwrap(),KlausOutcome::Timeout{pc:0x0600});
+        //JAM
+        assert!(matches!(run(&[0x02],&config(0)).unwrap(),KlausOutcome::Error{error:CpuError::Jammed{pc:0x0600},..}));
+        assert!(run(&[0;0x10000],&config(0)).is_err());
+    }
+
+    //KLAUS_FUNCTIONAL_TESTに6502_functional_test.binのパスを指定したときだけ実行する
+    #[test]
+    fn test_klaus_functional_test_binary(){
+        let path=match env::var("KLAUS_FUNCTIONAL_TEST"){
+            Ok(path)=>path,
+            Err(_)=>return,
+        };
+        let binary=fs::read(path).unwrap();
+        let outcome=run(&binary,&KlausConfig::functional_test()).unwrap();
+        assert!(outcome.passed(),"{}",outcome);
+    }
+
+    //KLAUS_DECIMAL_TESTに6502_decimal_test.binのパスを指定したときだけ実行する
+    #[test]
+    fn test_klaus_decimal_test_binary(){
+        let path=match env::var("KLAUS_DECIMAL_TEST"){
+            Ok(path)=>path,
+            Err(_)=>return,
+        };
+        let binary=fs::read(path).unwrap();
+        let outcome=run(&binary,&KlausConfig::decimal_test()).unwrap();
+        assert!(outcome.passed(),"{}",outcome);
+    }
+}
@@ -1,32 +1,421 @@
+pub mod asm;
+pub mod bench;
+pub mod bus;
+pub mod callstack;
+pub mod cartridge;
 pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod klaus;
 pub mod opcodes;
-use crate::cpu::CPU;
+pub mod processor_tests;
+pub mod profiler;
+pub mod repl;
+pub mod symbols;
+pub mod trace;
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::Mem;
+use crate::cpu::{CpuVariant, CPU};
+use crate::debugger::Debugger;
+use crate::profiler::Profiler;
+use crate::repl::Repl;
+use crate::symbols::SymbolTable;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self,BufWriter};
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime,UNIX_EPOCH};
+
+//gdbサブコマンドの待ち受けポート
+const GDB_PORT:u16=6502;
+
+//profileサブコマンドで実行するサイクル数(NESの1秒分)
+const PROFILE_CYCLES:u64=1_789_773;
+//profileで表示する行数
+const PROFILE_TOP:usize=20;
+
+//Easy6502のsnakeゲーム(0x0600に置く)
+const SNAKE_SOURCE:&str=include_str!("snake.asm");
+
+const USAGE:&str="usage:
+  emulator                        run the snake game
+  emulator trace <rom.nes> [pc]   print a nestest style trace (pc in hex, e.g. C000)
+  emulator disasm [file] [org]    disassemble a raw binary loaded at org (default 0600),
+                                  the PRG-ROM of an iNES file, or the snake game
+  emulator asm <src.s> <out>      assemble to a raw binary, or an iNES image if out ends with .nes
+  emulator harte <dir> [op...]    run ProcessorTests JSON files (e.g. nes6502/v1) and report per opcode
+  emulator klaus <bin> [success] [start] [load] [--decimal]
+                                  run a Klaus Dormann test binary until it traps
+                                  (defaults: 3469 0400 0000, the standard functional test;
+                                  --decimal: 024B 0200 0000 and fail if ERROR at $000B is set)
+  emulator bench [cycles]         measure emulated MHz on a busy loop (default 100000000 cycles)
+  emulator debug <file> [org]     debug a raw binary loaded at org (default 0600) or an iNES file
+                                  interactively (type h at the prompt for commands)
+  emulator gdb <file> [org] [--port n]
+                                  load like debug and serve the GDB remote protocol on
+                                  127.0.0.1 for gdb/lldb (default port 6502: target remote :6502)
+  emulator profile <file> [org] [--cycles n] [--top n] [--folded out]
+                                  run like debug (default 1789773 cycles, one NES second, or until
+                                  BRK for raw binaries) and print the hottest addresses and
+                                  subroutines; --folded writes collapsed stacks for flamegraph.pl
+  emulator dap                    speak the Debug Adapter Protocol on stdin/stdout for editors;
+                                  the launch request takes program (.s source), stopOnEntry and cpu
+
+trace, disasm, debug and profile accept --symbols <file> (repeatable): ca65 .dbg, FCEUX .nl
+(game.nes.0.nl for bank 0, game.nes.ram.nl), Mesen .mlb, .s/.asm source, or name = $addr lines
+disasm, harte, klaus, debug, gdb and profile accept --cpu <2a03|6502|65c02> (defaults: 2a03, 2a03, 6502, 2a03, 2a03, 2a03)";
 
 fn main(){
+    if let Err(e)=run(){
+        eprintln!("{}",e);
+        process::exit(1);
+    }
+}
+
+fn run()->Result<(),Box<dyn Error>>{
+    let mut args:Vec<String>=env::args().collect();
+    let variant=take_cpu_option(&mut args)?;
+    let port=take_option(&mut args,"--port")?;
+    let cycles=take_option(&mut args,"--cycles")?;
+    let top=take_option(&mut args,"--top")?;
+    let folded=take_option(&mut args,"--folded")?;
+    let decimal=take_flag(&mut args,"--decimal");
+    let mut symbol_files=vec![];
+    while let Some(path)=take_option(&mut args,"--symbols")?{
+        symbol_files.push(path);
+    }
+    match args.get(1).map(|arg|arg.as_str()){
+        None=>run_snake(),
+        Some("trace")=>match args.get(2){
+            Some(path)=>run_trace(path,args.get(3),&symbol_files),
+            None=>Err(USAGE.into()),
+        },
+        Some("disasm")=>run_disasm(args.get(2),args.get(3),&symbol_files,variant.unwrap_or_default()),
+        Some("asm")=>match (args.get(2),args.get(3)){
+            (Some(source),Some(out))=>run_asm(source,out),
+            _=>Err(USAGE.into()),
+        },
+        Some("harte")=>match args.get(2){
+            Some(dir)=>run_harte(dir,&args[3..],variant.unwrap_or_default()),
+            None=>Err(USAGE.into()),
+        },
+        Some("klaus")=>match args.get(2){
+            Some(path)=>run_klaus(path,&args[3..],decimal,variant),
+            None=>Err(USAGE.into()),
+        },
+        Some("bench")=>run_bench(args.get(2)),
+        Some("debug")=>match args.get(2){
+            Some(path)=>run_debug(path,args.get(3),&symbol_files,variant.unwrap_or_default()),
+            None=>Err(USAGE.into()),
+        },
+        Some("gdb")=>match args.get(2){
+            Some(path)=>run_gdb(path,args.get(3),port,variant.unwrap_or_default()),
+            None=>Err(USAGE.into()),
+        },
+        Some("profile")=>match args.get(2){
+            Some(path)=>{
+                let options=ProfileOptions{cycles,top,folded};
+                run_profile(path,args.get(3),&symbol_files,options,variant.unwrap_or_default())
+            }
+            None=>Err(USAGE.into()),
+        },
+        Some("dap")=>Ok(dap::serve()?),
+        Some(_)=>Err(USAGE.into()),
+    }
+}
+
+//"--cpu <name>"をargsから取り除いて返す
+fn take_cpu_option(args:&mut Vec<String>)->Result<Option<CpuVariant>,Box<dyn Error>>{
+    match take_option(args,"--cpu")?{
+        Some(name)=>Ok(Some(name.parse()?)),
+        None=>Ok(None),
+    }
+}
+
+//"<option> <value>"をargsから取り除いて値を返す
+fn take_option(args:&mut Vec<String>,option:&str)->Result<Option<String>,Box<dyn Error>>{
+    let index=match args.iter().position(|arg|arg==option){
+        Some(index)=>index,
+        None=>return Ok(None),
+    };
+    if index+1>=args.len(){
+        return Err(format!("{} needs a value",option).into());
+    }
+    let value=args.remove(index+1);
+    args.remove(index);
+    Ok(Some(value))
+}
+
+//"<flag>"をargsから取り除いて、あったかどうかを返す
+fn take_flag(args:&mut Vec<String>,flag:&str)->bool{
+    match args.iter().position(|arg|arg==flag){
+        Some(index)=>{
+            args.remove(index);
+            true
+        }
+        None=>false,
+    }
+}
+
+//--symbolsのファイルをまとめて読む。iNESのROMならNROMとしてバンクを置く
+fn load_symbol_files(paths:&[String],program:Option<&str>)->Result<SymbolTable,Box<dyn Error>>{
+    let mut symbols=SymbolTable::new();
+    for path in paths{
+        symbols.extend(SymbolTable::load(path)?);
+    }
+    if let Some(program)=program.filter(|_|!paths.is_empty()){
+        if let Ok(rom)=Rom::new(&fs::read(program)?){
+            symbols.map_nrom(rom.prg_rom.len());
+        }
+    }
+    Ok(symbols)
+}
+
+fn parse_hex_u16(text:&str)->Result<u16,Box<dyn Error>>{
+    let digits=text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits,16).map_err(|_|format!("invalid address: {}",text).into())
+}
+
+/// NESのROMを読み込み、各命令の直前の状態をnestest.logの形式で標準出力に書く
+fn run_trace(path:&str,start_pc:Option<&String>,symbol_files:&[String])->Result<(),Box<dyn Error>>{
+    let raw=fs::read(path)?;
+    let rom=Rom::new(&raw)?;
+    let symbols=load_symbol_files(symbol_files,Some(path))?;
+    let mut cpu=CPU::with_bus(Bus::new(rom));
+    cpu.power_on();
+    if let Some(pc)=start_pc{
+        cpu.program_counter=parse_hex_u16(pc)?;
+    }
+
+    let stdout=io::stdout();
+    let mut out=BufWriter::new(stdout.lock());
+    let mut result=Ok(());
+    cpu.run_with_callback(|cpu|{
+        if result.is_ok(){
+            result=if symbols.is_empty(){
+                trace::write_trace(cpu,&mut out)
+            }else{
+                trace::write_trace_with_symbols(cpu,&symbols,&mut out)
+            };
+        }
+    })?;
+    result?;
+    Ok(())
+}
+
+/// ファイル(iNESならPRG-ROM、それ以外は生のバイナリ)を逆アセンブルして標準出力に書く
+fn run_disasm(path:Option<&String>,origin:Option<&String>,symbol_files:&[String],variant:CpuVariant)->Result<(),Box<dyn Error>>{
+    let mut symbols=load_symbol_files(symbol_files,path.map(|path|path.as_str()))?;
+    let (bytes,origin)=match path{
+        //snakeは自分のラベルを付ける
+        None=>{
+            let assembly=asm::assemble_program(SNAKE_SOURCE)?;
+            symbols.extend(SymbolTable::from_assembly(&assembly));
+            (assembly.bytes,0x0600)
+        }
+        Some(path)=>{
+            let raw=fs::read(path)?;
+            if cartridge::is_ines(&raw){
+                (Rom::new(&raw)?.prg_rom,0x8000)
+            }else{
+                let origin=match origin{
+                    Some(origin)=>parse_hex_u16(origin)?,
+                    None=>0x0600,
+                };
+                (raw,origin)
+            }
+        }
+    };
+    print!("{}",disasm::listing_with_symbols(&disasm::disassemble_for(variant,&bytes,origin),&symbols));
+    Ok(())
+}
+
+/// アセンブルしてファイルに書く。出力先が.nesならiNESイメージにする
+fn run_asm(source:&str,out:&str)->Result<(),Box<dyn Error>>{
+    let program=asm::assemble_program(&fs::read_to_string(source)?)?;
+    let image=if out.to_ascii_lowercase().ends_with(".nes"){
+        program.to_ines()?
+    }else{
+        program.bytes
+    };
+    fs::write(out,image)?;
+    Ok(())
+}
+
+/// ProcessorTestsをオペコードごとに実行して結果を表示する。1つでも結果が違えばエラー
+fn run_harte(dir:&str,opcodes:&[String],variant:CpuVariant)->Result<(),Box<dyn Error>>{
+    let opcodes=opcodes
+        .iter()
+        .map(|op|u8::from_str_radix(op,16).map_err(|_|format!("invalid opcode: {}",op)))
+        .collect::<Result<Vec<u8>,String>>()?;
+    let reports=processor_tests::run_dir(Path::new(dir),&opcodes,variant)?;
+    for report in &reports{
+        println!("{}",report);
+    }
+    let total:usize=reports.iter().map(|report|report.total).sum();
+    let passed:usize=reports.iter().map(|report|report.passed()).sum();
+    let state_failures:usize=reports.iter().map(|report|report.state_failures).sum();
+    println!("{}/{} passed ({} opcodes)",passed,total,reports.len());
+    if state_failures>0{
+        return Err(format!("{} cases with wrong registers or RAM",state_failures).into());
+    }
+    Ok(())
+}
+
+/// Klaus Dormannのテストをトラップに入るまで実行する。成功用のトラップでなければエラー
+fn run_klaus(path:&str,addrs:&[String],decimal:bool,variant:Option<CpuVariant>)->Result<(),Box<dyn Error>>{
+    let binary=fs::read(path)?;
+    let mut config=if decimal{
+        klaus::KlausConfig::decimal_test()
+    }else{
+        klaus::KlausConfig::functional_test()
+    };
+    if let Some(variant)=variant{
+        config.variant=variant;
+    }
+    if let Some(addr)=addrs.first(){
+        config.success_addr=parse_hex_u16(addr)?;
+    }
+    if let Some(addr)=addrs.get(1){
+        config.start_pc=parse_hex_u16(addr)?;
+    }
+    if let Some(addr)=addrs.get(2){
+        config.load_addr=parse_hex_u16(addr)?;
+    }
+    let outcome=klaus::run(&binary,&config)?;
+    if !outcome.passed(){
+        return Err(outcome.to_string().into());
+    }
+    println!("{}",outcome);
+    Ok(())
+}
+
+/// 決めたサイクル数だけループを回して、エミュレートしたクロックを表示する
+fn run_bench(cycles:Option<&String>)->Result<(),Box<dyn Error>>{
+    let cycles=match cycles{
+        Some(cycles)=>cycles.parse().map_err(|_|format!("invalid cycle count: {}",cycles))?,
+        None=>100_000_000,
+    };
+    println!("{}",bench::run(cycles)?);
+    Ok(())
+}
+
+/// 対話型のデバッガを標準入出力で動かす
+fn run_debug(path:&str,origin:Option<&String>,symbol_files:&[String],variant:CpuVariant)->Result<(),Box<dyn Error>>{
+    let symbols=load_symbol_files(symbol_files,Some(path))?;
+    let stdin=io::stdin();
+    let stdout=io::stdout();
+    let mut out=stdout.lock();
+    match load_debug_target(path,origin,variant)?{
+        DebugTarget::Nes(debugger)=>{
+            let mut repl=Repl::new(*debugger);
+            repl.symbols=symbols;
+            repl.run(stdin.lock(),&mut out)?
+        }
+        DebugTarget::Flat(debugger)=>{
+            let mut repl=Repl::new(*debugger);
+            repl.symbols=symbols;
+            repl.run(stdin.lock(),&mut out)?
+        }
+    }
+    Ok(())
+}
+
+/// gdbやlldbからつなげるように、localhostでGDBのリモートプロトコルを待ち受ける
+fn run_gdb(path:&str,origin:Option<&String>,port:Option<String>,variant:CpuVariant)->Result<(),Box<dyn Error>>{
+    let port=match port{
+        Some(port)=>port.parse().map_err(|_|format!("invalid port: {}",port))?,
+        None=>GDB_PORT,
+    };
+    match load_debug_target(path,origin,variant)?{
+        DebugTarget::Nes(debugger)=>gdb::serve(*debugger,port)?,
+        DebugTarget::Flat(debugger)=>gdb::serve(*debugger,port)?,
+    }
+    Ok(())
+}
+
+//profileの--cycles,--top,--folded
+struct ProfileOptions{
+    cycles:Option<String>,
+    top:Option<String>,
+    folded:Option<String>,
+}
+
+/// debugと同じように読み込んで実行し、時間のかかっているところを表示する
+fn run_profile(
+    path:&str,
+    origin:Option<&String>,
+    symbol_files:&[String],
+    options:ProfileOptions,
+    variant:CpuVariant,
+)->Result<(),Box<dyn Error>>{
+    let cycles=match options.cycles{
+        Some(cycles)=>cycles.parse().map_err(|_|format!("invalid cycle count: {}",cycles))?,
+        None=>PROFILE_CYCLES,
+    };
+    let top=match options.top{
+        Some(top)=>top.parse().map_err(|_|format!("invalid count: {}",top))?,
+        None=>PROFILE_TOP,
+    };
+    let symbols=load_symbol_files(symbol_files,Some(path))?;
+    let mut profiler=Profiler::new();
+    //途中でJAMなどに当たっても、そこまでの結果は出す
+    let result=match load_debug_target(path,origin,variant)?{
+        DebugTarget::Nes(mut debugger)=>profiler.run(&mut debugger.cpu,cycles),
+        DebugTarget::Flat(mut debugger)=>profiler.run(&mut debugger.cpu,cycles),
+    };
+    print!("{}",profiler.report(&symbols,top));
+    if let Some(folded)=options.folded{
+        fs::write(folded,profiler.collapsed(&symbols))?;
+    }
+    result?;
+    Ok(())
+}
+
+//64KBのFlatMemoryを抱えるので箱に入れる
+enum DebugTarget{
+    Nes(Box<Debugger<Bus>>),
+    Flat(Box<Debugger>),
+}
+
+//iNESならNESのバスに載せて電源を入れ、それ以外は生のバイナリとしてoriginに置く
+fn load_debug_target(path:&str,origin:Option<&String>,variant:CpuVariant)->Result<DebugTarget,Box<dyn Error>>{
+    let raw=fs::read(path)?;
+    if cartridge::is_ines(&raw){
+        let mut debugger=Debugger::new(Bus::new(Rom::new(&raw)?));
+        debugger.cpu.variant=variant;
+        debugger.cpu.halt_on_brk=false;
+        debugger.cpu.power_on();
+        return Ok(DebugTarget::Nes(Box::new(debugger)));
+    }
+    let origin=match origin{
+        Some(origin)=>parse_hex_u16(origin)?,
+        None=>0x0600,
+    };
+    let mut debugger=Debugger::default();
+    debugger.cpu.variant=variant;
+    debugger.cpu.load_at(origin,&raw)?;
+    debugger.cpu.program_counter=origin;
+    Ok(DebugTarget::Flat(Box::new(debugger)))
+}
+
+fn run_snake()->Result<(),Box<dyn Error>>{
     let mut cpu=CPU::new();
+    cpu.load(asm::assemble(SNAKE_SOURCE)?)?;
+    cpu.power_on();
 
-    let game_code: Vec<u8> = vec![
-        0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02, 0x85,
-        0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9, 0x0f, 0x85,
-        0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85, 0x00, 0xa5, 0xfe,
-        0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xc3,
-        0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c, 0x38, 0x06, 0xa5, 0xff, 0xc9,
-        0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0, 0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60,
-        0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85, 0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0,
-        0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01, 0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02,
-        0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05, 0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06,
-        0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00, 0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07,
-        0xe6, 0x03, 0xe6, 0x03, 0x20, 0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06,
-        0xb5, 0x11, 0xc5, 0x11, 0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c,
-        0x35, 0x07, 0x60, 0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02,
-        0x4a, 0xb0, 0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9,
-        0x20, 0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
-        0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10, 0xb0,
-        0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5, 0x10, 0x29,
-        0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe, 0x91, 0x00, 0x60,
-        0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10, 0x60, 0xa2, 0x00, 0xea,
-        0xea, 0xca, 0xd0, 0xfb, 0x60
-    ];
-
-    cpu.load_and_run(game_code);
+    let mut seed=SystemTime::now().duration_since(UNIX_EPOCH).map(|d|d.subsec_nanos()).unwrap_or(1)|1;
+    cpu.run_with_callback(move |cpu|{
+        //Easy6502と同じく0xFEには毎命令乱数を書き込む(xorshift)
+        seed^=seed<<13;
+        seed^=seed>>17;
+        seed^=seed<<5;
+        cpu.mem_write(0xfe,(seed%16+1) as u8);
+    })?;
+    Ok(())
 }
\ No newline at end of file
@@ -1,6 +1,5 @@
-use crate ::cpu::AddressingMode;
+use crate ::cpu::{AddressingMode, CpuVariant};
 use lazy_static::lazy_static;
-use std::collections::HashMap;
 
 pub struct OpCode{
     pub code:u8,
@@ -8,65 +7,31 @@ pub struct OpCode{
     pub len:u8,
     pub cycles:u8,
     pub mode:AddressingMode,
+    pub unofficial:bool,//公式ドキュメントにない命令(illegal opcode)
 }
 
 impl OpCode{
     fn new(code:u8,mnemonic:&'static str,len:u8,cycles:u8,mode:AddressingMode)->Self{
         OpCode{
-            code:code,
-            mnemonic:mnemonic,
-            len:len,
-            cycles:cycles,
-            mode:mode,
+            code,
+            mnemonic,
+            len,
+            cycles,
+            mode,
+            unofficial:false,
+        }
+    }
+
+    fn new_unofficial(code:u8,mnemonic:&'static str,len:u8,cycles:u8,mode:AddressingMode)->Self{
+        OpCode{
+            unofficial:true,
+            ..OpCode::new(code,mnemonic,len,cycles,mode)
         }
     }
 }
 
 lazy_static!{
     pub static ref CPU_OPS_CODES:Vec<OpCode>=vec![
-    //     OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing),
-    //     OpCode::new(0xaa, "TAX", 1, 2, AddressingMode::NoneAddressing),
-    //     OpCode::new(0xe8, "INX", 1, 2, AddressingMode::NoneAddressing),
-    //     OpCode::new(0xa2, "LDX", 2, 2, AddressingMode::Immediate),
-
-    //     OpCode::new(0xa9, "LDA", 2, 2, AddressingMode::Immediate),
-    //     OpCode::new(0xa5, "LDA", 2, 3, AddressingMode::ZeroPage),
-    //     OpCode::new(0xb5, "LDA", 2, 4, AddressingMode::ZeroPage_X),
-    //     OpCode::new(0xad, "LDA", 3, 4, AddressingMode::Absolute),
-    //     OpCode::new(0xbd, "LDA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
-    //     OpCode::new(0xb9, "LDA", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
-    //     OpCode::new(0xa1, "LDA", 2, 6, AddressingMode::Indirect_X),
-    //     OpCode::new(0xb1, "LDA", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
-
-    //     OpCode::new(0x85, "STA", 2, 3, AddressingMode::ZeroPage),
-    //     OpCode::new(0x95, "STA", 2, 4, AddressingMode::ZeroPage_X),
-    //     OpCode::new(0x8d, "STA", 3, 4, AddressingMode::Absolute),
-    //     OpCode::new(0x9d, "STA", 3, 5, AddressingMode::Absolute_X),
-    //     OpCode::new(0x99, "STA", 3, 5, AddressingMode::Absolute_Y),
-    //     OpCode::new(0x81, "STA", 2, 6, AddressingMode::Indirect_X),
-    //     OpCode::new(0x91, "STA", 2, 6, AddressingMode::Indirect_Y),
-
-    //     OpCode::new(0x69,"ADC",2,2,AddressingMode::Immediate),
-    //     OpCode::new(0x65,"ADC",2,3,AddressingMode::ZeroPage),
-    //     OpCode::new(0x75,"ADC",2,4,AddressingMode::ZeroPage_X),
-    //     OpCode::new(0x6D,"ADC",3,4,AddressingMode::Absolute),
-    //     OpCode::new(0x7D,"ADC",3,4,AddressingMode::Absolute_X),
-    //     OpCode::new(0x79,"ADC",3,4,AddressingMode::Absolute_Y),
-    //     OpCode::new(0x61,"ADC",2,6,AddressingMode::Indirect_X),
-    //     OpCode::new(0x71,"ADC",2,5,AddressingMode::Indirect_Y),
-
-    //     OpCode::new(0x29,"AND",2,2,AddressingMode::Immediate),
-    //     OpCode::new(0x25,"AND",2,2,AddressingMode::ZeroPage),
-    //     OpCode::new(0x35,"AND",2,2,AddressingMode::ZeroPage_X),
-    //     OpCode::new(0x2D,"AND",2,3,AddressingMode::Absolute),
-    //     OpCode::new(0x3D,"AND",2,3,AddressingMode::Absolute_X),
-    //     OpCode::new(0x39,"AND",2,3,AddressingMode::Absolute_Y),
-    //     OpCode::new(0x21,"AND",2,2,AddressingMode::Indirect_X),
-    //     OpCode::new(0x31,"AND",2,2,AddressingMode::Indirect_Y),
-
-
-
-        
         OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing),
         OpCode::new(0xea, "NOP", 1, 2, AddressingMode::NoneAddressing),
 
@@ -117,25 +82,25 @@ lazy_static!{
         OpCode::new(0x11, "ORA", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
 
         /* Shifts */
-        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x0e, "ASL", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x1e, "ASL", 3, 7, AddressingMode::Absolute_X),
 
-        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x4e, "LSR", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x5e, "LSR", 3, 7, AddressingMode::Absolute_X),
 
-        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x2e, "ROL", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x3e, "ROL", 3, 7, AddressingMode::Absolute_X),
 
-        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x76, "ROR", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x6e, "ROR", 3, 6, AddressingMode::Absolute),
@@ -177,22 +142,22 @@ lazy_static!{
 
         /* Branching */
 
-        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::NoneAddressing), //AddressingMode that acts as Immidiate
-        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::NoneAddressing), //AddressingMode:Indirect with 6502 bug
+        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::Absolute),
+        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::Indirect), //6502 bug:ページをまたぐ間接参照はできない
 
-        OpCode::new(0x20, "JSR", 3, 6, AddressingMode::NoneAddressing),
+        OpCode::new(0x20, "JSR", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x60, "RTS", 1, 6, AddressingMode::NoneAddressing),
 
         OpCode::new(0x40, "RTI", 1, 6, AddressingMode::NoneAddressing),
 
-        OpCode::new(0xd0, "BNE", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x70, "BVS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x50, "BVC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x30, "BMI", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0xf0, "BEQ", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0xb0, "BCS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x90, "BCC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x10, "BPL", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+        OpCode::new(0xd0, "BNE", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x70, "BVS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x50, "BVC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x30, "BMI", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0xf0, "BEQ", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0xb0, "BCS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x90, "BCC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x10, "BPL", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
 
         OpCode::new(0x24, "BIT", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0x2c, "BIT", 3, 4, AddressingMode::Absolute),
@@ -261,13 +226,277 @@ lazy_static!{
         OpCode::new(0x08, "PHP", 1, 3, AddressingMode::NoneAddressing),
         OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing),
 
+        /* Unofficial */
+        //JAM(KIL):CPUが停止する
+        OpCode::new_unofficial(0x02, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x12, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x22, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x32, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x42, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x52, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x62, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x72, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x92, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xb2, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xd2, "JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xf2, "JAM", 1, 2, AddressingMode::NoneAddressing),
+
+        OpCode::new_unofficial(0x1a, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x3a, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x5a, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x7a, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xda, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xfa, "NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x80, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x82, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x89, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0xc2, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0xe2, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x04, "NOP", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x44, "NOP", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x64, "NOP", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x14, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x34, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x54, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x74, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0xd4, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0xf4, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x0c, "NOP", 3, 4, AddressingMode::Absolute),
+        OpCode::new_unofficial(0x1c, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0x3c, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0x5c, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0x7c, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0xdc, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0xfc, "NOP", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+
+        OpCode::new_unofficial(0xa7, "LAX", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0xb7, "LAX", 2, 4, AddressingMode::ZeroPage_Y),
+        OpCode::new_unofficial(0xaf, "LAX", 3, 4, AddressingMode::Absolute),
+        OpCode::new_unofficial(0xbf, "LAX", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0xa3, "LAX", 2, 6, AddressingMode::Indirect_X),
+        OpCode::new_unofficial(0xb3, "LAX", 2, 5/*+1 if page crossed*/, AddressingMode::Indirect_Y),
+
+        OpCode::new_unofficial(0x87, "SAX", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x97, "SAX", 2, 4, AddressingMode::ZeroPage_Y),
+        OpCode::new_unofficial(0x8f, "SAX", 3, 4, AddressingMode::Absolute),
+        OpCode::new_unofficial(0x83, "SAX", 2, 6, AddressingMode::Indirect_X),
+
+        OpCode::new_unofficial(0xeb, "SBC", 2, 2, AddressingMode::Immediate),
+
+        OpCode::new_unofficial(0xc7, "DCP", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0xd7, "DCP", 2, 6, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0xcf, "DCP", 3, 6, AddressingMode::Absolute),
+        OpCode::new_unofficial(0xdf, "DCP", 3, 7, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0xdb, "DCP", 3, 7, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0xc3, "DCP", 2, 8, AddressingMode::Indirect_X),
+        OpCode::new_unofficial(0xd3, "DCP", 2, 8, AddressingMode::Indirect_Y),
+
+        OpCode::new_unofficial(0xe7, "ISB", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0xf7, "ISB", 2, 6, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0xef, "ISB", 3, 6, AddressingMode::Absolute),
+        OpCode::new_unofficial(0xff, "ISB", 3, 7, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0xfb, "ISB", 3, 7, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0xe3, "ISB", 2, 8, AddressingMode::Indirect_X),
+        OpCode::new_unofficial(0xf3, "ISB", 2, 8, AddressingMode::Indirect_Y),
+
+        OpCode::new_unofficial(0x07, "SLO", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x17, "SLO", 2, 6, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x0f, "SLO", 3, 6, AddressingMode::Absolute),
+        OpCode::new_unofficial(0x1f, "SLO", 3, 7, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0x1b, "SLO", 3, 7, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0x03, "SLO", 2, 8, AddressingMode::Indirect_X),
+        OpCode::new_unofficial(0x13, "SLO", 2, 8, AddressingMode::Indirect_Y),
+
+        OpCode::new_unofficial(0x27, "RLA", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x37, "RLA", 2, 6, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x2f, "RLA", 3, 6, AddressingMode::Absolute),
+        OpCode::new_unofficial(0x3f, "RLA", 3, 7, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0x3b, "RLA", 3, 7, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0x23, "RLA", 2, 8, AddressingMode::Indirect_X),
+        OpCode::new_unofficial(0x33, "RLA", 2, 8, AddressingMode::Indirect_Y),
+
+        OpCode::new_unofficial(0x47, "SRE", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x57, "SRE", 2, 6, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x4f, "SRE", 3, 6, AddressingMode::Absolute),
+        OpCode::new_unofficial(0x5f, "SRE", 3, 7, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0x5b, "SRE", 3, 7, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0x43, "SRE", 2, 8, AddressingMode::Indirect_X),
+        OpCode::new_unofficial(0x53, "SRE", 2, 8, AddressingMode::Indirect_Y),
+
+        OpCode::new_unofficial(0x67, "RRA", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x77, "RRA", 2, 6, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x6f, "RRA", 3, 6, AddressingMode::Absolute),
+        OpCode::new_unofficial(0x7f, "RRA", 3, 7, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0x7b, "RRA", 3, 7, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0x63, "RRA", 2, 8, AddressingMode::Indirect_X),
+        OpCode::new_unofficial(0x73, "RRA", 2, 8, AddressingMode::Indirect_Y),
+
+        OpCode::new_unofficial(0x0b, "ANC", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x2b, "ANC", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x4b, "ALR", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x6b, "ARR", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0xcb, "AXS", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0xbb, "LAS", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_Y),
+
+        //以下は実機でも結果が不安定な命令
+        OpCode::new_unofficial(0x8b, "XAA", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0xab, "LXA", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x9f, "AHX", 3, 5, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0x93, "AHX", 2, 6, AddressingMode::Indirect_Y),
+        OpCode::new_unofficial(0x9b, "TAS", 3, 5, AddressingMode::Absolute_Y),
+        OpCode::new_unofficial(0x9c, "SHY", 3, 5, AddressingMode::Absolute_X),
+        OpCode::new_unofficial(0x9e, "SHX", 3, 5, AddressingMode::Absolute_Y),
+
     ];
 
-    pub static ref OPCODES_MAP:HashMap<u8,&'static OpCode>={
-        let mut map=HashMap::new();
+    /// オペコードをそのまま添字にして引く表。1命令ごとにハッシュを計算しないで済む
+    pub static ref OPCODE_TABLE:[Option<&'static OpCode>;256]={
+        let mut table=[None;256];
         for cpuop in &*CPU_OPS_CODES{
-            map.insert(cpuop.code,cpuop);
+            table[cpuop.code as usize]=Some(cpuop);
         }
-        map
+        table
     };
-}
\ No newline at end of file
+
+    /// 65C02で追加・変更された命令。それ以外はNMOSの公式命令と同じ
+    pub static ref CMOS_OPS_CODES:Vec<OpCode>=vec![
+        //新しい命令
+        OpCode::new(0x80, "BRA", 2, 3/*+1 if to a new page*/, AddressingMode::Relative),
+        OpCode::new(0xda, "PHX", 1, 3, AddressingMode::NoneAddressing),
+        OpCode::new(0x5a, "PHY", 1, 3, AddressingMode::NoneAddressing),
+        OpCode::new(0xfa, "PLX", 1, 4, AddressingMode::NoneAddressing),
+        OpCode::new(0x7a, "PLY", 1, 4, AddressingMode::NoneAddressing),
+        OpCode::new(0x64, "STZ", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(0x74, "STZ", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x9c, "STZ", 3, 4, AddressingMode::Absolute),
+        OpCode::new(0x9e, "STZ", 3, 5, AddressingMode::Absolute_X),
+        OpCode::new(0x04, "TSB", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x0c, "TSB", 3, 6, AddressingMode::Absolute),
+        OpCode::new(0x14, "TRB", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x1c, "TRB", 3, 6, AddressingMode::Absolute),
+        OpCode::new(0xcb, "WAI", 1, 3, AddressingMode::NoneAddressing),
+        OpCode::new(0xdb, "STP", 1, 3, AddressingMode::NoneAddressing),
+
+        //既存の命令の新しいアドレッシングモード
+        OpCode::new(0x89, "BIT", 2, 2, AddressingMode::Immediate),
+        OpCode::new(0x34, "BIT", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x3c, "BIT", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new(0x1a, "INC", 1, 2, AddressingMode::Accumulator),
+        OpCode::new(0x3a, "DEC", 1, 2, AddressingMode::Accumulator),
+        OpCode::new(0x7c, "JMP", 3, 6, AddressingMode::Absolute_X_Indirect),
+        OpCode::new(0x12, "ORA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x32, "AND", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x52, "EOR", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x72, "ADC", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x92, "STA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xb2, "LDA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xd2, "CMP", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xf2, "SBC", 2, 5, AddressingMode::ZeroPage_Indirect),
+
+        //サイクル数が変わった命令
+        OpCode::new(0x6c, "JMP", 3, 6, AddressingMode::Indirect),
+        OpCode::new(0x1e, "ASL", 3, 6/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new(0x3e, "ROL", 3, 6/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new(0x5e, "LSR", 3, 6/*+1 if page crossed*/, AddressingMode::Absolute_X),
+        OpCode::new(0x7e, "ROR", 3, 6/*+1 if page crossed*/, AddressingMode::Absolute_X),
+
+        //ビット操作(RockwellとWDC)
+        OpCode::new(0x07, "RMB0", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x17, "RMB1", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x27, "RMB2", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x37, "RMB3", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x47, "RMB4", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x57, "RMB5", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x67, "RMB6", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x77, "RMB7", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x87, "SMB0", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x97, "SMB1", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0xa7, "SMB2", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0xb7, "SMB3", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0xc7, "SMB4", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0xd7, "SMB5", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0xe7, "SMB6", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0xf7, "SMB7", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x0f, "BBR0", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x1f, "BBR1", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x2f, "BBR2", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x3f, "BBR3", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x4f, "BBR4", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x5f, "BBR5", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x6f, "BBR6", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x7f, "BBR7", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x8f, "BBS0", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0x9f, "BBS1", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0xaf, "BBS2", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0xbf, "BBS3", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0xcf, "BBS4", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0xdf, "BBS5", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0xef, "BBS6", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+        OpCode::new(0xff, "BBS7", 3, 5/*+1 if branch succeeds +1 if to a new page*/, AddressingMode::ZeroPage_Relative),
+
+        //NMOSの非公式命令の場所は何もしないNOPになる
+        OpCode::new_unofficial(0x02, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x22, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x42, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x62, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x82, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0xc2, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0xe2, "NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new_unofficial(0x44, "NOP", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new_unofficial(0x54, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0xd4, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0xf4, "NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new_unofficial(0x5c, "NOP", 3, 8, AddressingMode::Absolute),
+        OpCode::new_unofficial(0xdc, "NOP", 3, 4, AddressingMode::Absolute),
+        OpCode::new_unofficial(0xfc, "NOP", 3, 4, AddressingMode::Absolute),
+        OpCode::new_unofficial(0x03, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x13, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x23, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x33, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x43, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x53, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x63, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x73, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x83, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x93, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xa3, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xb3, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xc3, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xd3, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xe3, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xf3, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x0b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x1b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x2b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x3b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x4b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x5b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x6b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x7b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x8b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0x9b, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xab, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xbb, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xeb, "NOP", 1, 1, AddressingMode::NoneAddressing),
+        OpCode::new_unofficial(0xfb, "NOP", 1, 1, AddressingMode::NoneAddressing),
+    ];
+
+    pub static ref CMOS_OPCODE_TABLE:[Option<&'static OpCode>;256]={
+        let mut table=[None;256];
+        for cpuop in CPU_OPS_CODES.iter().filter(|op|!op.unofficial){
+            table[cpuop.code as usize]=Some(cpuop);
+        }
+        for cpuop in &*CMOS_OPS_CODES{
+            table[cpuop.code as usize]=Some(cpuop);
+        }
+        table
+    };
+}
+
+/// CPUの種類に合わせた命令表。2A03とNMOSの6502は同じ命令を持つ
+pub fn table(variant:CpuVariant)->&'static [Option<&'static OpCode>;256]{
+    match variant{
+        CpuVariant::Wdc65C02=>&CMOS_OPCODE_TABLE,
+        CpuVariant::Ricoh2A03|CpuVariant::Nmos6502=>&OPCODE_TABLE,
+    }
+}
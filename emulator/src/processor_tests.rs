@@ -0,0 +1,329 @@
+// Tom HarteのProcessorTests(SingleStepTests)の6502用JSONを実行する
+//
+// https://github.com/SingleStepTests/ProcessorTests の`6502/v1`や`nes6502/v1`を
+// ローカルに置き、そのディレクトリを指定する。ファイルはオペコードごとに`a9.json`のような名前で、
+// 1ファイルに1万ケースほど入っている
+use crate::bus::{BusAccess, BusCycle, RecordingBus};
+use crate::cpu::{CpuFlags, CpuRegisters, CpuVariant, Mem, CPU};
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// テストの前後のCPUとRAMの状態
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct CpuState{
+    pub pc:u16,
+    pub s:u8,
+    pub a:u8,
+    pub x:u8,
+    pub y:u8,
+    pub p:u8,
+    pub ram:Vec<(u16,u8)>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct TestCase{
+    pub name:String,
+    pub initial:CpuState,
+    pub expected:CpuState,
+    pub cycles:Vec<BusCycle>,
+}
+
+/// 1ケースの結果。stateはレジスタとRAM、busはサイクルごとのバスの動きの食い違い
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct CaseResult{
+    pub state:Vec<String>,
+    pub bus:Vec<String>,
+}
+
+impl CaseResult{
+    pub fn passed(&self)->bool{
+        self.state.is_empty()&&self.bus.is_empty()
+    }
+}
+
+fn field<'a>(value:&'a Value,key:&str)->Result<&'a Value,String>{
+    value.get(key).ok_or_else(||format!("missing field '{}'",key))
+}
+
+fn number(value:&Value,what:&str)->Result<u64,String>{
+    value.as_u64().ok_or_else(||format!("'{}' is not a number",what))
+}
+
+fn byte(value:&Value,what:&str)->Result<u8,String>{
+    u8::try_from(number(value,what)?).map_err(|_|format!("'{}' does not fit in a byte",what))
+}
+
+fn word(value:&Value,what:&str)->Result<u16,String>{
+    u16::try_from(number(value,what)?).map_err(|_|format!("'{}' does not fit in a word",what))
+}
+
+fn parse_state(value:&Value)->Result<CpuState,String>{
+    let mut ram=vec![];
+    for entry in field(value,"ram")?.as_array().ok_or("'ram' is not an array")?{
+        match entry.as_array().map(|pair|pair.as_slice()){
+            Some([addr,data])=>ram.push((word(addr,"ram address")?,byte(data,"ram value")?)),
+            _=>return Err("ram entry must be [address, value]".to_string()),
+        }
+    }
+    Ok(CpuState{
+        pc:word(field(value,"pc")?,"pc")?,
+        s:byte(field(value,"s")?,"s")?,
+        a:byte(field(value,"a")?,"a")?,
+        x:byte(field(value,"x")?,"x")?,
+        y:byte(field(value,"y")?,"y")?,
+        p:byte(field(value,"p")?,"p")?,
+        ram,
+    })
+}
+
+fn parse_cycle(value:&Value)->Result<BusCycle,String>{
+    match value.as_array().map(|cycle|cycle.as_slice()){
+        Some([addr,data,access])=>{
+            let access=match access.as_str(){
+                Some("read")=>BusAccess::Read,
+                Some("write")=>BusAccess::Write,
+                _=>return Err(format!("unknown bus access {}",access)),
+            };
+            Ok(BusCycle{addr:word(addr,"cycle address")?,value:byte(data,"cycle value")?,access})
+        }
+        _=>Err("cycle must be [address, value, \"read\"|\"write\"]".to_string()),
+    }
+}
+
+/// JSONのテキストからテストケースを読む
+pub fn parse_cases(text:&str)->Result<Vec<TestCase>,String>{
+    let json:Value=serde_json::from_str(text).map_err(|e|e.to_string())?;
+    let mut cases=vec![];
+    for case in json.as_array().ok_or("top level is not an array")?{
+        let name=field(case,"name")?.as_str().unwrap_or_default().to_string();
+        let cycles=field(case,"cycles")?
+            .as_array()
+            .ok_or("'cycles' is not an array")?
+            .iter()
+            .map(parse_cycle)
+            .collect::<Result<Vec<BusCycle>,String>>()
+            .map_err(|e|format!("{}: {}",name,e))?;
+        cases.push(TestCase{
+            initial:parse_state(field(case,"initial")?).map_err(|e|format!("{}: {}",name,e))?,
+            expected:parse_state(field(case,"final")?).map_err(|e|format!("{}: {}",name,e))?,
+            name,
+            cycles,
+        });
+    }
+    Ok(cases)
+}
+
+/// 1ケースを実行して期待値と比べる。6502/v1はNmos6502、nes6502/v1はRicoh2A03で実行する
+pub fn run_case(case:&TestCase,variant:CpuVariant)->CaseResult{
+    let mut cpu=CPU::with_bus(RecordingBus::new());
+    cpu.halt_on_brk=false;
+    cpu.variant=variant;
+    for (addr,data) in &case.initial.ram{
+        cpu.bus.poke(*addr,*data);
+    }
+    let initial=&case.initial;
+    cpu.set_registers(CpuRegisters{
+        a:initial.a,
+        x:initial.x,
+        y:initial.y,
+        p:CpuFlags::from_bits_truncate(initial.p),
+        sp:initial.s,
+        pc:initial.pc,
+        cycles:0,
+    });
+
+    let mut result=CaseResult::default();
+    if let Err(e)=cpu.step(){
+        result.state.push(e.to_string());
+        return result;
+    }
+
+    let expected=&case.expected;
+    let registers=cpu.registers();
+    let mut check=|name:&str,expected:u16,actual:u16,width:usize|{
+        if expected!=actual{
+            result.state.push(format!("{}: expected ${:0w$X}, got ${:0w$X}",name,expected,actual,w=width));
+        }
+    };
+    check("PC",expected.pc,registers.pc,4);
+    check("SP",expected.s as u16,registers.sp as u16,2);
+    check("A",expected.a as u16,registers.a as u16,2);
+    check("X",expected.x as u16,registers.x as u16,2);
+    check("Y",expected.y as u16,registers.y as u16,2);
+    //Bとbit5はレジスタに存在しないので比べない
+    check("P",(expected.p|0b0011_0000) as u16,(registers.p.bits()|0b0011_0000) as u16,2);
+    for (addr,data) in &expected.ram{
+        let actual=cpu.bus.peek(*addr);
+        if actual!=*data{
+            result.state.push(format!("RAM ${:04X}: expected ${:02X}, got ${:02X}",addr,data,actual));
+        }
+    }
+
+    let accesses=&cpu.bus.accesses;
+    for i in 0..case.cycles.len().max(accesses.len()){
+        match (case.cycles.get(i),accesses.get(i)){
+            (Some(expected),Some(actual)) if expected==actual=>{}
+            (Some(expected),Some(actual))=>result.bus.push(format!("cycle {}: expected {}, got {}",i+1,expected,actual)),
+            (Some(expected),None)=>result.bus.push(format!("cycle {}: expected {}, got nothing",i+1,expected)),
+            (None,Some(actual))=>result.bus.push(format!("cycle {}: unexpected {}",i+1,actual)),
+            (None,None)=>unreachable!(),
+        }
+    }
+    if cpu.cycles!=case.cycles.len(){
+        result.bus.push(format!("took {} cycles, expected {}",cpu.cycles,case.cycles.len()));
+    }
+    result
+}
+
+/// オペコード1つ分の集計
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct OpcodeReport{
+    pub opcode:u8,
+    pub total:usize,
+    pub state_failures:usize,//レジスタかRAMが違う
+    pub bus_failures:usize,//結果は合っていてもバスの動きが違う
+    pub first_failure:Option<(String,CaseResult)>,
+}
+
+impl OpcodeReport{
+    pub fn passed(&self)->usize{
+        self.total-self.state_failures-self.bus_failures
+    }
+}
+
+impl fmt::Display for OpcodeReport{
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result{
+        write!(f,"{:02x}: {}/{} passed",self.opcode,self.passed(),self.total)?;
+        if self.state_failures>0||self.bus_failures>0{
+            write!(f," ({} state, {} bus)",self.state_failures,self.bus_failures)?;
+        }
+        if let Some((name,result))=&self.first_failure{
+            let first=result.state.first().or(result.bus.first()).map(|s|s.as_str()).unwrap_or_default();
+            write!(f,"\n    first failure \"{}\": {}",name,first)?;
+        }
+        Ok(())
+    }
+}
+
+/// ケースをすべて実行して集計する
+pub fn run_cases(opcode:u8,cases:&[TestCase],variant:CpuVariant)->OpcodeReport{
+    let mut report=OpcodeReport{opcode,total:cases.len(),state_failures:0,bus_failures:0,first_failure:None};
+    for case in cases{
+        let result=run_case(case,variant);
+        if result.passed(){
+            continue;
+        }
+        if result.state.is_empty(){
+            report.bus_failures+=1;
+        }else{
+            report.state_failures+=1;
+        }
+        if report.first_failure.is_none(){
+            report.first_failure=Some((case.name.clone(),result));
+        }
+    }
+    report
+}
+
+/// dirにある"xx.json"を実行する。opcodesが空ならファイルのあるものすべて
+pub fn run_dir(dir:&Path,opcodes:&[u8],variant:CpuVariant)->Result<Vec<OpcodeReport>,String>{
+    let targets:Vec<u8>=if opcodes.is_empty(){(0..=0xFF).collect()}else{opcodes.to_vec()};
+    let mut reports=vec![];
+    for opcode in targets{
+        let path=dir.join(format!("{:02x}.json",opcode));
+        if !path.exists(){
+            if opcodes.is_empty(){
+                continue;
+            }
+            return Err(format!("{} does not exist",path.display()));
+        }
+        let text=fs::read_to_string(&path).map_err(|e|format!("{}: {}",path.display(),e))?;
+        let cases=parse_cases(&text).map_err(|e|format!("{}: {}",path.display(),e))?;
+        reports.push(run_cases(opcode,&cases,variant));
+    }
+    if reports.is_empty(){
+        return Err(format!("no test files in {}",dir.display()));
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+
+    //ProcessorTestsの6502/v1/a9.jsonの形式
+    const LDA_IMMEDIATE:&str=r#"[
+        {
+            "name": "a9 80 5e",
+            "initial": {"pc": 1536, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[1536, 169], [1537, 128]]},
+            "final": {"pc": 1538, "s": 253, "a": 128, "x": 0, "y": 0, "p": 164, "ram": [[1536, 169], [1537, 128]]},
+            "cycles": [[1536, 169, "read"], [1537, 128, "read"]]
+        }
+    ]"#;
+
+    #[test]
+    fn test_parse_cases(){
+        let cases=parse_cases(LDA_IMMEDIATE).unwrap();
+        assert_eq!(cases.len(),1);
+        assert_eq!(cases[0].name,"a9 80 5e");
+        assert_eq!(cases[0].initial.ram,vec![(0x0600,0xa9),(0x0601,0x80)]);
+        assert_eq!(cases[0].expected.a,0x80);
+        assert_eq!(cases[0].cycles[1],BusCycle{addr:0x0601,value:0x80,access:BusAccess::Read});
+        assert!(parse_cases(r#"[{"name": "x"}]"#).is_err());
+    }
+
+    #[test]
+    fn test_run_case_passes(){
+        let cases=parse_cases(LDA_IMMEDIATE).unwrap();
+        let report=run_cases(0xa9,&cases,CpuVariant::Ricoh2A03);
+        assert_eq!(report.passed(),1,"{}",report);
+    }
+
+    #[test]
+    fn test_run_case_reports_mismatches(){
+        let mut case=parse_cases(LDA_IMMEDIATE).unwrap().remove(0);
+        case.expected.a=0x81;
+        case.expected.ram.push((0x0010,0x01));
+        case.cycles.push(BusCycle{addr:0x0602,value:0x00,access:BusAccess::Read});
+        let result=run_case(&case,CpuVariant::Ricoh2A03);
+        assert_eq!(result.state,vec![
+            "A: expected $81, got $80".to_string(),
+            "RAM $0010: expected $01, got $00".to_string(),
+        ]);
+        assert_eq!(result.bus[0],"cycle 3: expected read $0602=$00, got nothing");
+
+        let report=run_cases(0xa9,&[case],CpuVariant::Ricoh2A03);
+        assert_eq!(report.state_failures,1);
+        assert!(report.to_string().starts_with("a9: 0/1 passed (1 state, 0 bus)\n    first failure \"a9 80 5e\""));
+    }
+
+    //PROCESSOR_TESTS_DIRにnes6502/v1などのディレクトリを指定したときだけ実行する
+    //(例: PROCESSOR_TESTS_DIR=../ProcessorTests/nes6502/v1 cargo test processor_tests -- --nocapture)
+    //6502/v1などを試すときはPROCESSOR_TESTS_CPU=6502のようにCPUも指定する
+    //バスの動きの違いも失敗にする。結果だけ確かめたいときはPROCESSOR_TESTS_IGNORE_BUS=1
+    #[test]
+    fn test_processor_tests_fixtures(){
+        let dir=match env::var("PROCESSOR_TESTS_DIR"){
+            Ok(dir)=>dir,
+            Err(_)=>return,
+        };
+        let variant=match env::var("PROCESSOR_TESTS_CPU"){
+            Ok(name)=>name.parse().unwrap(),
+            Err(_)=>CpuVariant::Ricoh2A03,
+        };
+        let check_bus=env::var("PROCESSOR_TESTS_IGNORE_BUS").is_err();
+        let reports=run_dir(Path::new(&dir),&[],variant).unwrap();
+        for report in &reports{
+            println!("{}",report);
+        }
+        let failed:Vec<String>=reports
+            .iter()
+            .filter(|report|report.state_failures>0||(check_bus&&report.bus_failures>0))
+            .map(|report|format!("{:02x}",report.opcode))
+            .collect();
+        assert!(failed.is_empty(),"opcodes with mismatches: {}",failed.join(" "));
+    }
+}
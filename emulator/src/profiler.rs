@@ -0,0 +1,300 @@
+// 実行プロファイラ
+//
+// 1命令ずつ実行しながら、PCごとの命令数とサイクル数、呼び出しの経路(シャドウコールスタック)ごとの
+// サイクル数を数える。サブルーチンごとの集計は経路から作り、flamegraph.plやinfernoに渡せる
+// collapsed stack形式("root;main;draw 1234")でも書き出せる
+use crate::callstack::{CallStack, FrameKind};
+use crate::cpu::{CpuError, Mem, StepResult, CPU};
+use crate::symbols::SymbolTable;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+/// 命令数とサイクル数
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct Counts{
+    pub instructions:u64,
+    pub cycles:u64,
+}
+
+impl Counts{
+    fn add(&mut self,other:Counts){
+        self.instructions+=other.instructions;
+        self.cycles+=other.cycles;
+    }
+}
+
+/// サブルーチン(割り込みハンドラも含む)ごとの集計
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct RoutineProfile{
+    pub addr:u16,
+    pub calls:u64,
+    pub self_counts:Counts,//このサブルーチンの中の命令だけ
+    pub total_counts:Counts,//呼んだ先も含む。再帰しても1回だけ数える
+}
+
+pub struct Profiler{
+    by_pc:Vec<Counts>,//65536アドレス分
+    stacks:HashMap<Vec<u16>,Counts>,//呼び出したサブルーチンの先頭を外側から並べた経路ごと
+    calls:HashMap<u16,u64>,
+    path:Vec<u16>,//今の経路
+    pub total:Counts,
+}
+
+impl Default for Profiler{
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+impl Profiler{
+    pub fn new()->Self{
+        Profiler{
+            by_pc:vec![Counts::default();0x10000],
+            stacks:HashMap::new(),
+            calls:HashMap::new(),
+            path:vec![],
+            total:Counts::default(),
+        }
+    }
+
+    /// 1命令実行して数える。cpuのcall_stackがなければ付ける
+    pub fn step<M:Mem>(&mut self,cpu:&mut CPU<M>)->Result<StepResult,CpuError>{
+        let pushed=cpu.call_stack.get_or_insert_with(CallStack::new).pushed();
+        let mut pc=cpu.program_counter;
+        let result=cpu.step()?;
+
+        let calls=cpu.call_stack.get_or_insert_with(CallStack::new);
+        let frames=calls.frames();
+        let new=((calls.pushed()-pushed) as usize).min(frames.len());
+        let entered=&frames[frames.len()-new..];
+        for frame in entered{
+            *self.calls.entry(frame.target).or_default()+=1;
+        }
+        //NMI,IRQで入ったときは、割り込みの手順もハンドラの最初の命令の分として数える
+        let interrupt=entered.first().filter(|frame|matches!(frame.kind,FrameKind::Nmi|FrameKind::Irq));
+        if !result.halted{
+            if let Some(frame)=interrupt{
+                pc=frame.target;
+                self.path.push(frame.target);
+            }
+            let counts=Counts{instructions:1,cycles:result.cycles as u64};
+            self.by_pc[pc as usize].add(counts);
+            self.total.add(counts);
+            match self.stacks.get_mut(&self.path){
+                Some(stack)=>stack.add(counts),
+                None=>{
+                    self.stacks.insert(self.path.clone(),counts);
+                }
+            }
+        }
+
+        //JSRの分までは呼び出し元の経路で数えたので、ここで次の命令の経路にする
+        if self.path.len()!=frames.len()||self.path.iter().zip(frames).any(|(addr,frame)|*addr!=frame.target){
+            self.path.clear();
+            self.path.extend(frames.iter().map(|frame|frame.target));
+        }
+        Ok(result)
+    }
+
+    /// BRK(halt_on_brkのとき)で止まるか、cyclesを使い切るまで実行する
+    pub fn run<M:Mem>(&mut self,cpu:&mut CPU<M>,cycles:u64)->Result<(),CpuError>{
+        let end=self.total.cycles+cycles;
+        while self.total.cycles<end{
+            if self.step(cpu)?.halted{
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn at(&self,pc:u16)->Counts{
+        self.by_pc[pc as usize]
+    }
+
+    /// 一度でも実行したアドレスを、サイクル数の多い順に
+    pub fn hot_spots(&self)->Vec<(u16,Counts)>{
+        let mut spots:Vec<(u16,Counts)>=self
+            .by_pc
+            .iter()
+            .enumerate()
+            .filter(|(_,counts)|counts.instructions>0)
+            .map(|(pc,counts)|(pc as u16,*counts))
+            .collect();
+        spots.sort_by(|a,b|b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    /// 呼ばれたサブルーチンを、呼んだ先も含めたサイクル数の多い順に
+    pub fn subroutines(&self)->Vec<RoutineProfile>{
+        let mut routines:BTreeMap<u16,RoutineProfile>=BTreeMap::new();
+        for (path,counts) in &self.stacks{
+            let mut seen=HashSet::new();
+            for (i,addr) in path.iter().enumerate(){
+                let routine=routines.entry(*addr).or_insert(RoutineProfile{
+                    addr:*addr,
+                    calls:self.calls.get(addr).copied().unwrap_or(0),
+                    self_counts:Counts::default(),
+                    total_counts:Counts::default(),
+                });
+                if i==path.len()-1{
+                    routine.self_counts.add(*counts);
+                }
+                if seen.insert(*addr){
+                    routine.total_counts.add(*counts);
+                }
+            }
+        }
+        let mut routines:Vec<RoutineProfile>=routines.into_values().collect();
+        routines.sort_by(|a,b|b.total_counts.cycles.cmp(&a.total_counts.cycles).then(a.addr.cmp(&b.addr)));
+        routines
+    }
+
+    /// 上位top件のアドレスとサブルーチンの表
+    pub fn report(&self,symbols:&SymbolTable,top:usize)->String{
+        let total=self.total.cycles.max(1) as f64;
+        let percent=|cycles:u64|cycles as f64*100.0/total;
+        let mut text=String::new();
+        writeln!(text,"{} instructions, {} cycles",self.total.instructions,self.total.cycles).unwrap();
+
+        writeln!(text,"\nhot spots:\n      cycles       %  instructions  address").unwrap();
+        for (pc,counts) in self.hot_spots().into_iter().take(top){
+            writeln!(
+                text,
+                "{:>12} {:>6.2}% {:>13}  {}",
+                counts.cycles,percent(counts.cycles),counts.instructions,symbols.label(pc)
+            ).unwrap();
+        }
+
+        writeln!(text,"\nsubroutines:\n       calls  self cycles       %  total cycles       %  subroutine").unwrap();
+        for routine in self.subroutines().into_iter().take(top){
+            writeln!(
+                text,
+                "{:>12} {:>12} {:>6.2}% {:>13} {:>6.2}%  {}",
+                routine.calls,
+                routine.self_counts.cycles,
+                percent(routine.self_counts.cycles),
+                routine.total_counts.cycles,
+                percent(routine.total_counts.cycles),
+                symbols.label(routine.addr)
+            ).unwrap();
+        }
+        text
+    }
+
+    /// flamegraph.pl/inferno-flamegraph用のcollapsed stack形式。値はサイクル数
+    pub fn collapsed(&self,symbols:&SymbolTable)->String{
+        let mut lines:Vec<String>=self
+            .stacks
+            .iter()
+            .map(|(path,counts)|{
+                let mut line="root".to_string();
+                for addr in path{
+                    line.push(';');
+                    match symbols.name_at(*addr){
+                        Some(name)=>line.push_str(name),
+                        None=>write!(line,"${:04X}",addr).unwrap(),
+                    }
+                }
+                write!(line," {}",counts.cycles).unwrap();
+                line
+            })
+            .collect();
+        lines.sort();
+        let mut text=lines.join("\n");
+        text.push('\n');
+        text
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::asm::assemble_program;
+
+    //leafを3回呼ぶループ。middleはleafを呼んでから戻る
+    const PROGRAM:&str="
+        ldx #3          ; $0600
+    loop:
+        jsr middle      ; $0602
+        dex             ; $0605
+        bne loop        ; $0606
+        brk             ; $0608
+    middle:
+        jsr leaf        ; $0609
+        rts             ; $060C
+    leaf:
+        nop             ; $060D
+        rts             ; $060E
+";
+
+    fn profile()->(Profiler,SymbolTable){
+        let assembly=assemble_program(PROGRAM).unwrap();
+        let mut cpu=CPU::new();
+        cpu.load(assembly.bytes.clone()).unwrap();
+        let mut profiler=Profiler::new();
+        profiler.run(&mut cpu,1_000_000).unwrap();
+        (profiler,SymbolTable::from_assembly(&assembly))
+    }
+
+    #[test]
+    fn test_counts_per_pc(){
+        let (profiler,_)=profile();
+        assert_eq!(profiler.at(0x0600),Counts{instructions:1,cycles:2});
+        assert_eq!(profiler.at(0x0602),Counts{instructions:3,cycles:18});
+        assert_eq!(profiler.at(0x060D),Counts{instructions:3,cycles:6});
+        //BNEは2回分岐(3サイクル)して1回抜ける(2サイクル)
+        assert_eq!(profiler.at(0x0606),Counts{instructions:3,cycles:8});
+        //止まったBRKは数えない
+        assert_eq!(profiler.at(0x0608),Counts::default());
+        assert_eq!(profiler.total,Counts{instructions:22,cycles:94});
+        assert_eq!(profiler.hot_spots()[0].0,0x0602);
+    }
+
+    #[test]
+    fn test_subroutines_and_collapsed(){
+        let (profiler,symbols)=profile();
+        let routines=profiler.subroutines();
+        assert_eq!(routines.len(),2);
+        //middle: JSR leaf(6)+RTS(6)を3回。leafの分も合わせる
+        assert_eq!(routines[0].addr,0x0609);
+        assert_eq!(routines[0].calls,3);
+        assert_eq!(routines[0].self_counts,Counts{instructions:6,cycles:36});
+        assert_eq!(routines[0].total_counts,Counts{instructions:12,cycles:60});
+        assert_eq!(routines[1].self_counts,Counts{instructions:6,cycles:24});
+        assert_eq!(
+            profiler.collapsed(&symbols),
+            "root 34\nroot;middle 36\nroot;middle;leaf 24\n"
+        );
+        let report=profiler.report(&symbols,2);
+        assert!(report.starts_with("22 instructions, 94 cycles\n"),"{}",report);
+        assert!(report.contains("          18  19.15%             3  $0602 <loop>\n"),"{}",report);
+        assert!(report.contains("           3           36  38.30%            60  63.83%  $0609 <middle>\n"),"{}",report);
+    }
+
+    #[test]
+    fn test_interrupt_entry_counts_in_handler(){
+        let assembly=assemble_program("
+            cli
+        loop:
+            jmp loop    ; $0601
+        handler:
+            nop         ; $0604
+            rti         ; $0605
+        ").unwrap();
+        let mut cpu=CPU::new();
+        cpu.load(assembly.bytes).unwrap();
+        cpu.mem_write_u16(0xFFFE,0x0604);
+        let mut profiler=Profiler::new();
+        profiler.step(&mut cpu).unwrap();
+        cpu.set_irq(true);
+        profiler.step(&mut cpu).unwrap();
+        cpu.set_irq(false);
+        profiler.step(&mut cpu).unwrap();
+        //割り込みの7サイクルはハンドラの最初の命令に付ける
+        assert_eq!(profiler.at(0x0604),Counts{instructions:1,cycles:9});
+        assert_eq!(profiler.at(0x0605),Counts{instructions:1,cycles:6});
+        assert_eq!(profiler.at(0x0601),Counts::default());
+        assert_eq!(profiler.subroutines()[0].calls,1);
+        assert_eq!(profiler.collapsed(&SymbolTable::new()),"root 2\nroot;$0604 15\n");
+    }
+}
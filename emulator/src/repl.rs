@@ -0,0 +1,442 @@
+// 対話型のデバッガ(emulator debug prog.bin)
+//
+// 1行に1コマンドを読み、Debuggerを動かして結果を書く。アドレスは16進("0200","$0200")かシンボル名で書ける
+use crate::cpu::Mem;
+use crate::debugger::{Breakpoint, Debugger, StopReason, WatchKind, Watchpoint};
+use crate::disasm::{self, Instruction};
+use crate::symbols::SymbolTable;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+
+const HELP:&str="commands:
+  b [addr [cond]]          set a breakpoint (cond like x==$10), or list breakpoints
+  d <n>                    delete breakpoint n
+  w [r|w|a <start> [end]]  set a read/write/access watchpoint, or list watchpoints
+  dw <n>                   delete watchpoint n
+  s [count]                step into
+  n                        step over (runs a JSR to its return)
+  o                        step out (runs until RTS/RTI leaves this routine)
+  c                        continue
+  u <addr>                 run until addr
+  r                        show registers
+  m <start> [end]          dump memory (end is inclusive)
+  m <addr> = <byte>...     write bytes
+  dis [addr] [count]       disassemble (default: around PC)
+  stack                    show the stack ($0100+SP+1 to $01FF)
+  bt                       show the calls (JSR, NMI, IRQ, BRK) that led here, and stack tricks
+                           (PLA/TXS, pushed return addresses) seen since the last bt
+  sym <file>               add symbols (ca65 .dbg, FCEUX .nl, Mesen .mlb, .s/.asm source,
+                           or lines of \"name = $addr\")
+  q                        quit";
+
+//disで前後に表示する命令の数
+const CONTEXT_BEFORE:usize=3;
+const CONTEXT_AFTER:usize=6;
+//disで一度に表示する命令の上限。1byteずつでもアドレス空間を一周する数
+const MAX_DISASSEMBLE:usize=0x10000;
+
+pub struct Repl<M:Mem>{
+    pub debugger:Debugger<M>,
+    pub symbols:SymbolTable,
+}
+
+impl<M:Mem> Repl<M>{
+    pub fn new(debugger:Debugger<M>)->Self{
+        Repl{debugger,symbols:SymbolTable::new()}
+    }
+
+    /// qかinputの終わりまでコマンドを読んで実行する
+    pub fn run<R:BufRead,W:Write>(&mut self,input:R,out:&mut W)->io::Result<()>{
+        self.show_location(out)?;
+        write!(out,"(debug) ")?;
+        out.flush()?;
+        for line in input.lines(){
+            if !self.execute(&line?,out)?{
+                return Ok(());
+            }
+            write!(out,"(debug) ")?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    /// 1行を実行する。qならfalseを返す。コマンドの誤りはoutに書いて続ける
+    pub fn execute<W:Write>(&mut self,line:&str,out:&mut W)->io::Result<bool>{
+        let words:Vec<&str>=line.split_whitespace().collect();
+        match self.command(&words,out){
+            Ok(running)=>Ok(running),
+            Err(error)=>{
+                writeln!(out,"error: {}",error)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn command<W:Write>(&mut self,words:&[&str],out:&mut W)->Result<bool,Box<dyn Error>>{
+        let (name,args)=match words.split_first(){
+            Some((name,args))=>(*name,args),
+            None=>return Ok(true),
+        };
+        match name{
+            "b"|"break"=>self.breakpoint(args,out)?,
+            "d"|"delete"=>{
+                let index=parse_index(args.first(),self.debugger.breakpoints.len())?;
+                self.debugger.breakpoints.remove(index);
+            }
+            "w"|"watch"=>self.watchpoint(args,out)?,
+            "dw"=>{
+                let index=parse_index(args.first(),self.debugger.watchpoints().len())?;
+                self.debugger.watchpoints().remove(index);
+            }
+            "s"|"step"=>{
+                let count=match args.first(){
+                    Some(count)=>count.parse().map_err(|_|format!("invalid count: {}",count))?,
+                    None=>1,
+                };
+                let mut reason=StopReason::Step{pc:self.debugger.cpu.program_counter};
+                for _ in 0..count{
+                    reason=self.debugger.step_into();
+                    if !matches!(reason,StopReason::Step{..}){
+                        break;
+                    }
+                }
+                self.stopped(&reason,out)?;
+            }
+            "n"|"next"=>{
+                let reason=self.debugger.step_over();
+                self.stopped(&reason,out)?;
+            }
+            "o"|"finish"=>{
+                let reason=self.debugger.step_out();
+                self.stopped(&reason,out)?;
+            }
+            "c"|"continue"=>{
+                let reason=self.debugger.resume();
+                self.stopped(&reason,out)?;
+            }
+            "u"|"until"=>{
+                let addr=self.parse_addr(args.first().ok_or("u needs an address")?)?;
+                let reason=self.debugger.run_until(addr);
+                self.stopped(&reason,out)?;
+            }
+            "r"|"regs"=>self.show_registers(out)?,
+            "m"|"mem"=>self.memory(args,out)?,
+            "dis"=>self.disassemble(args,out)?,
+            "stack"=>self.show_stack(out)?,
+            "bt"|"backtrace"=>self.show_backtrace(out)?,
+            "sym"=>{
+                let path=args.first().ok_or("sym needs a file")?;
+                let symbols=SymbolTable::load(path)?;
+                let count=symbols.len();
+                self.symbols.extend(symbols);
+                writeln!(out,"loaded {} symbols",count)?;
+            }
+            "h"|"help"=>writeln!(out,"{}",HELP)?,
+            "q"|"quit"=>return Ok(false),
+            _=>return Err(format!("unknown command: {} (h for help)",name).into()),
+        }
+        Ok(true)
+    }
+
+    fn breakpoint<W:Write>(&mut self,args:&[&str],out:&mut W)->Result<(),Box<dyn Error>>{
+        let addr=match args.first(){
+            Some(addr)=>self.parse_addr(addr)?,
+            None=>{
+                for (i,breakpoint) in self.debugger.breakpoints.iter().enumerate(){
+                    match breakpoint.condition{
+                        Some(condition)=>writeln!(out,"{}: {} if {}",i,self.symbols.label(breakpoint.addr),condition)?,
+                        None=>writeln!(out,"{}: {}",i,self.symbols.label(breakpoint.addr))?,
+                    }
+                }
+                return Ok(());
+            }
+        };
+        //条件は"x == 3"のように空白を入れて書いてもよい
+        let condition=match args[1..].join(""){
+            condition if condition.is_empty()=>None,
+            condition=>Some(condition.parse()?),
+        };
+        self.debugger.breakpoints.push(Breakpoint{addr,condition});
+        writeln!(out,"breakpoint {} at {}",self.debugger.breakpoints.len()-1,self.symbols.label(addr))?;
+        Ok(())
+    }
+
+    fn watchpoint<W:Write>(&mut self,args:&[&str],out:&mut W)->Result<(),Box<dyn Error>>{
+        let kind=match args.first(){
+            Some(&"r")=>WatchKind::Read,
+            Some(&"w")=>WatchKind::Write,
+            Some(&"a")=>WatchKind::Access,
+            Some(kind)=>return Err(format!("watchpoint kind must be r, w or a: {}",kind).into()),
+            None=>{
+                for (i,watchpoint) in self.debugger.watchpoints().iter().enumerate(){
+                    writeln!(out,"{}: {:?} ${:04X}-${:04X}",i,watchpoint.kind,watchpoint.start,watchpoint.end)?;
+                }
+                return Ok(());
+            }
+        };
+        let start=self.parse_addr(args.get(1).ok_or("w needs an address")?)?;
+        let end=match args.get(2){
+            Some(end)=>self.parse_addr(end)?,
+            None=>start,
+        };
+        self.debugger.watchpoints().push(Watchpoint{start,end,kind});
+        writeln!(out,"watchpoint {} on ${:04X}-${:04X}",self.debugger.watchpoints().len()-1,start,end)?;
+        Ok(())
+    }
+
+    fn memory<W:Write>(&mut self,args:&[&str],out:&mut W)->Result<(),Box<dyn Error>>{
+        let start=self.parse_addr(args.first().ok_or("m needs an address")?)?;
+        if args.get(1)==Some(&"="){
+            for (i,byte) in args[2..].iter().enumerate(){
+                let value=u8::from_str_radix(byte.trim_start_matches('$'),16)
+                    .map_err(|_|format!("invalid byte: {}",byte))?;
+                self.debugger.cpu.mem_write(start.wrapping_add(i as u16),value);
+            }
+            return Ok(());
+        }
+        let end=match args.get(1){
+            Some(end)=>self.parse_addr(end)?,
+            None=>start.saturating_add(0x0F),
+        };
+        if end<start{
+            return Err("end address is before start".into());
+        }
+        let mut addr=start as u32;
+        while addr<=end as u32{
+            let line_end=(addr+16).min(end as u32+1);
+            let bytes:Vec<String>=(addr..line_end)
+                .map(|a|format!("{:02X}",self.debugger.cpu.peek(a as u16)))
+                .collect();
+            writeln!(out,"{:04X}  {}",addr,bytes.join(" "))?;
+            addr=line_end;
+        }
+        Ok(())
+    }
+
+    fn disassemble<W:Write>(&mut self,args:&[&str],out:&mut W)->Result<(),Box<dyn Error>>{
+        let pc=self.debugger.cpu.program_counter;
+        let instructions=match args.first(){
+            Some(addr)=>{
+                let addr=self.parse_addr(addr)?;
+                let count=match args.get(1){
+                    Some(count)=>count.parse().map_err(|_|format!("invalid count: {}",count))?,
+                    None=>CONTEXT_BEFORE+CONTEXT_AFTER,
+                };
+                self.decode_from(addr,count.min(MAX_DISASSEMBLE))
+            }
+            None=>self.around(pc),
+        };
+        for instruction in &instructions{
+            if let Some(name)=self.symbols.name_at(instruction.addr){
+                writeln!(out,"{}:",name)?;
+            }
+            let marker=if instruction.addr==pc{"=>"}else{"  "};
+            writeln!(out,"{} {}",marker,self.format_instruction(instruction))?;
+        }
+        Ok(())
+    }
+
+    fn decode_from(&mut self,addr:u16,count:usize)->Vec<Instruction>{
+        let variant=self.debugger.cpu.variant;
+        let mut instructions=Vec::with_capacity(count);
+        let mut addr=addr;
+        for _ in 0..count{
+            let instruction=disasm::decode_at_for(variant,&mut self.debugger.cpu,addr);
+            addr=instruction.next_addr();
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    //命令の長さは後ろからは分からないので、pcのちょうど上に並ぶ一番遠い開始位置を探す
+    fn around(&mut self,pc:u16)->Vec<Instruction>{
+        let mut before=vec![];
+        for back in (1..=(CONTEXT_BEFORE as u16)*3).rev(){
+            let mut addr=pc.wrapping_sub(back);
+            let mut decoded=vec![];
+            while addr!=pc&&pc.wrapping_sub(addr)<=back{
+                let instruction=disasm::decode_at_for(self.debugger.cpu.variant,&mut self.debugger.cpu,addr);
+                addr=instruction.next_addr();
+                decoded.push(instruction);
+            }
+            if addr==pc{
+                before=decoded;
+                break;
+            }
+        }
+        let skip=before.len().saturating_sub(CONTEXT_BEFORE);
+        let mut instructions:Vec<Instruction>=before.into_iter().skip(skip).collect();
+        instructions.extend(self.decode_from(pc,CONTEXT_AFTER));
+        instructions
+    }
+
+    fn format_instruction(&self,instruction:&Instruction)->String{
+        disasm::format_line_with_symbols(instruction,&self.symbols)
+    }
+
+    fn show_registers<W:Write>(&mut self,out:&mut W)->io::Result<()>{
+        let r=self.debugger.cpu.registers();
+        writeln!(
+            out,
+            "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} [{}] SP:{:02X} PC:{} CYC:{}",
+            r.a,r.x,r.y,r.p.bits(),r.p.letters(),r.sp,self.symbols.label(r.pc),r.cycles
+        )
+    }
+
+    fn show_backtrace<W:Write>(&mut self,out:&mut W)->io::Result<()>{
+        let backtrace=self.debugger.cpu.backtrace();
+        if backtrace.is_empty(){
+            writeln!(out,"no calls")?;
+        }
+        for (i,frame) in backtrace.iter().enumerate(){
+            writeln!(
+                out,
+                "#{:<2} {} from {} at {}, returns to {}",
+                i,self.symbols.label(frame.target),frame.kind,self.symbols.label(frame.caller),self.symbols.label(frame.return_addr)
+            )?;
+        }
+        if let Some(calls)=&mut self.debugger.cpu.call_stack{
+            for mismatch in calls.take_mismatches(){
+                writeln!(out,"warning: {}",mismatch)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn show_stack<W:Write>(&mut self,out:&mut W)->io::Result<()>{
+        let sp=self.debugger.cpu.registers().sp;
+        if sp==0xFF{
+            return writeln!(out,"stack is empty");
+        }
+        for addr in (0x0100|(sp as u16+1))..=0x01FF{
+            writeln!(out,"{:04X}  {:02X}",addr,self.debugger.cpu.peek(addr))?;
+        }
+        Ok(())
+    }
+
+    fn show_location<W:Write>(&mut self,out:&mut W)->io::Result<()>{
+        let pc=self.debugger.cpu.program_counter;
+        let instruction=disasm::decode_at_for(self.debugger.cpu.variant,&mut self.debugger.cpu,pc);
+        writeln!(out,"=> {}",self.format_instruction(&instruction))
+    }
+
+    fn stopped<W:Write>(&mut self,reason:&StopReason,out:&mut W)->io::Result<()>{
+        match reason{
+            //ステップ実行は止まった場所を見れば分かる
+            StopReason::Step{..}=>{}
+            _=>writeln!(out,"{}",reason)?,
+        }
+        self.show_location(out)
+    }
+
+    //シンボル名を優先し、なければ16進として読む
+    fn parse_addr(&self,text:&str)->Result<u16,String>{
+        if let Some(addr)=self.symbols.lookup(text){
+            return Ok(addr);
+        }
+        let digits=text.trim_start_matches('$').trim_start_matches("0x");
+        u16::from_str_radix(digits,16).map_err(|_|format!("invalid address or unknown symbol: {}",text))
+    }
+}
+
+fn parse_index(text:Option<&&str>,len:usize)->Result<usize,String>{
+    let text=text.ok_or("needs a number")?;
+    match text.parse(){
+        Ok(index) if index<len=>Ok(index),
+        _=>Err(format!("no such entry: {}",text)),
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::asm::assemble_program;
+    use crate::bus::FlatMemory;
+
+    const PROGRAM:&str="
+        ldx #0
+    loop:
+        inx
+        stx $0200
+        cpx #3
+        bne loop
+        jsr done
+        brk
+    done:
+        rts
+";
+
+    fn repl()->Repl<FlatMemory>{
+        let assembly=assemble_program(PROGRAM).unwrap();
+        let mut debugger=Debugger::default();
+        debugger.cpu.load(assembly.bytes.clone()).unwrap();
+        let mut repl=Repl::new(debugger);
+        repl.symbols=SymbolTable::from_assembly(&assembly);
+        repl
+    }
+
+    //コマンドを順に実行して出力をまとめて返す
+    fn script(repl:&mut Repl<FlatMemory>,commands:&[&str])->String{
+        let mut out=vec![];
+        for command in commands{
+            assert!(repl.execute(command,&mut out).unwrap());
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoint_continue_and_registers(){
+        let mut repl=repl();
+        let out=script(&mut repl,&["b loop x == 2","c","r"]);
+        assert!(out.contains("breakpoint 0 at $0602 <loop>"),"{}",out);
+        assert!(out.contains("breakpoint 0 at $0602\n=> 0602  E8        INX"),"{}",out);
+        assert!(out.contains("A:00 X:02 Y:00 P:A0 [Nv-bdizc] SP:FD PC:$0602 <loop> CYC:24"),"{}",out);
+        let out=script(&mut repl,&["d 0","c"]);
+        assert!(out.contains("BRK at $060D"),"{}",out);
+    }
+
+    #[test]
+    fn test_step_and_disassemble(){
+        let mut repl=repl();
+        let out=script(&mut repl,&["s 2","dis"]);
+        assert!(out.contains("=> 0603  8E 00 02  STX $0200"),"{}",out);
+        assert!(out.contains("loop:\n   0602  E8        INX"),"{}",out);
+        assert!(out.contains("   0600  A2 00     LDX #$00"),"{}",out);
+        let out=script(&mut repl,&["u 060A","n"]);
+        assert!(out.contains("reached $060A\n=> 060A  20 0E 06  JSR done"),"{}",out);
+        assert!(out.contains("=> 060D  00        BRK"),"{}",out);
+        let out=script(&mut repl,&["dis 0 18446744073709551615"]);
+        assert_eq!(out.lines().filter(|line|line.starts_with("   ")||line.starts_with("=>")).count(),MAX_DISASSEMBLE);
+    }
+
+    #[test]
+    fn test_memory_and_stack(){
+        let mut repl=repl();
+        let out=script(&mut repl,&["m 0200 = 01 02 ff","m 0200 0211","stack"]);
+        assert!(out.contains("0200  01 02 FF 00 00 00 00 00 00 00 00 00 00 00 00 00\n0210  00 00\n"),"{}",out);
+        assert!(out.contains("01FE  00\n01FF  00\n"),"{}",out);
+        let out=script(&mut repl,&["u done","stack"]);
+        assert!(out.contains("01FC  0C\n01FD  06\n01FE  00\n"),"{}",out);
+    }
+
+    #[test]
+    fn test_backtrace(){
+        let mut repl=repl();
+        let out=script(&mut repl,&["bt","u done","bt"]);
+        assert!(out.contains("no calls\n"),"{}",out);
+        assert!(out.contains("#0  $060E <done> from JSR at $060A, returns to $060D\n"),"{}",out);
+        //戻り先を捨ててからRTSすると、btで知らせる
+        let out=script(&mut repl,&["m 01FC = 00","s","bt"]);
+        assert!(out.contains("warning: $060E: returned to $0601 instead of $060D"),"{}",out);
+    }
+
+    #[test]
+    fn test_errors_and_quit(){
+        let mut repl=repl();
+        let out=script(&mut repl,&["b nowhere","d 5","frobnicate"]);
+        assert!(out.contains("error: invalid address or unknown symbol: nowhere"),"{}",out);
+        assert!(out.contains("error: no such entry: 5"),"{}",out);
+        assert!(out.contains("error: unknown command: frobnicate"),"{}",out);
+        assert!(!repl.execute("q",&mut vec![]).unwrap());
+    }
+}
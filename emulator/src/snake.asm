@@ -0,0 +1,276 @@
+; Easy6502のsnakeゲーム (https://skilldrick.github.io/easy6502/)
+; W A S Dで向きを変える
+
+; ゼロページの変数
+.org $00
+appleL:         .res 1 ; りんごの画面上の位置(下位)
+appleH:         .res 1 ; りんごの画面上の位置(上位)
+snakeDirection: .res 1 ; 進む向き(下の値のどれか)
+snakeLength:    .res 1 ; 長さ(byte数)
+.org $10
+snakeHeadL:     .res 1 ; 頭の位置(下位)
+snakeHeadH:     .res 1 ; 頭の位置(上位)
+snakeBodyStart:        ; ここから体の位置が2byteずつ並ぶ
+
+; 向き(1bitずつ)
+define movingUp      1
+define movingRight   2
+define movingDown    4
+define movingLeft    8
+
+; 向きを変えるキーのASCIIコード
+define ASCII_w      $77
+define ASCII_a      $61
+define ASCII_s      $73
+define ASCII_d      $64
+
+; システム変数
+.org $fe
+sysRandom:    .res 1
+sysLastKey:   .res 1
+
+.org $0600
+
+
+  jsr init
+  jsr loop
+
+init:
+  jsr initSnake
+  jsr generateApplePosition
+  rts
+
+
+initSnake:
+  lda #movingRight ; 最初の向き
+  sta snakeDirection
+
+  lda #4 ; 最初の長さ(2節)
+  sta snakeLength
+
+  lda #$11
+  sta snakeHeadL
+
+  lda #$10
+  sta snakeBodyStart
+
+  lda #$0f
+  sta $14 ; 体の1節目
+
+  lda #$04
+  sta snakeHeadH
+  sta $13 ; 体の1節目
+  sta $15 ; 体の2節目
+  rts
+
+
+generateApplePosition:
+  ; 下位は乱数そのまま
+  lda sysRandom
+  sta appleL
+
+  ; 上位は2~5の乱数
+  lda sysRandom
+  and #$03
+  clc
+  adc #2
+  sta appleH
+
+  rts
+
+
+loop:
+  jsr readKeys
+  jsr checkCollision
+  jsr updateSnake
+  jsr drawApple
+  jsr drawSnake
+  jsr spinWheels
+  jmp loop
+
+
+readKeys:
+  lda sysLastKey
+  cmp #ASCII_w
+  beq upKey
+  cmp #ASCII_d
+  beq rightKey
+  cmp #ASCII_s
+  beq downKey
+  cmp #ASCII_a
+  beq leftKey
+  rts
+upKey:
+  lda #movingDown
+  bit snakeDirection
+  bne illegalMove
+
+  lda #movingUp
+  sta snakeDirection
+  rts
+rightKey:
+  lda #movingLeft
+  bit snakeDirection
+  bne illegalMove
+
+  lda #movingRight
+  sta snakeDirection
+  rts
+downKey:
+  lda #movingUp
+  bit snakeDirection
+  bne illegalMove
+
+  lda #movingDown
+  sta snakeDirection
+  rts
+leftKey:
+  lda #movingRight
+  bit snakeDirection
+  bne illegalMove
+
+  lda #movingLeft
+  sta snakeDirection
+  rts
+illegalMove:
+  rts
+
+
+checkCollision:
+  jsr checkAppleCollision
+  jsr checkSnakeCollision
+  rts
+
+
+checkAppleCollision:
+  lda appleL
+  cmp snakeHeadL
+  bne doneCheckingAppleCollision
+  lda appleH
+  cmp snakeHeadH
+  bne doneCheckingAppleCollision
+
+  ; りんごを食べたら2byte伸びる
+  inc snakeLength
+  inc snakeLength
+  jsr generateApplePosition
+doneCheckingAppleCollision:
+  rts
+
+
+checkSnakeCollision:
+  ldx #2 ; 2節目から調べる
+snakeCollisionLoop:
+  lda snakeHeadL,x
+  cmp snakeHeadL
+  bne continueCollisionLoop
+
+maybeCollided:
+  lda snakeHeadH,x
+  cmp snakeHeadH
+  beq didCollide
+
+continueCollisionLoop:
+  inx
+  inx
+  cpx snakeLength ; 最後まで衝突しなかった
+  beq didntCollide
+  jmp snakeCollisionLoop
+
+didCollide:
+  jmp gameOver
+didntCollide:
+  rts
+
+
+updateSnake:
+  ldx snakeLength
+  dex
+  txa
+updateloop:
+  lda snakeHeadL,x
+  sta snakeBodyStart,x
+  dex
+  bpl updateloop
+
+  lda snakeDirection
+  lsr
+  bcs up
+  lsr
+  bcs right
+  lsr
+  bcs down
+  lsr
+  bcs left
+up:
+  lda snakeHeadL
+  sec
+  sbc #$20
+  sta snakeHeadL
+  bcc upup
+  rts
+upup:
+  dec snakeHeadH
+  lda #$1
+  cmp snakeHeadH
+  beq collision
+  rts
+right:
+  inc snakeHeadL
+  lda #$1f
+  bit snakeHeadL
+  beq collision
+  rts
+down:
+  lda snakeHeadL
+  clc
+  adc #$20
+  sta snakeHeadL
+  bcs downdown
+  rts
+downdown:
+  inc snakeHeadH
+  lda #$6
+  cmp snakeHeadH
+  beq collision
+  rts
+left:
+  dec snakeHeadL
+  lda snakeHeadL
+  and #$1f
+  cmp #$1f
+  beq collision
+  rts
+collision:
+  jmp gameOver
+
+
+drawApple:
+  ldy #0
+  lda sysRandom
+  sta (appleL),y
+  rts
+
+
+drawSnake:
+  ldx snakeLength
+  lda #0
+  sta (snakeHeadL,x) ; しっぽの端を消す
+
+  ldx #0
+  lda #1
+  sta (snakeHeadL,x) ; 頭を描く
+  rts
+
+
+spinWheels:
+  ldx #0
+spinloop:
+  nop
+  nop
+  dex
+  bne spinloop
+  rts
+
+
+gameOver:
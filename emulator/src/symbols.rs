@@ -0,0 +1,431 @@
+// ラベル名とアドレスの対応表
+//
+// デバッガでブレークポイントを名前で指定したり、逆アセンブルやトレースにラベルを付けたりするのに使う。
+// 自前のアセンブラのほか、ca65の.dbg、FCEUXの.nl、Mesenの.mlbを読める。
+// ROMのラベルは16KBのPRGバンクごとに持ち、banksで$8000~と$C000~に見えているバンクを選ぶ
+use crate::asm::{self, Assembly};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+const PRG_BANK_SIZE:usize=0x4000;
+const PRG_ROM:u16=0x8000;
+const PRG_RAM:u16=0x6000;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+struct Symbol{
+    addr:u16,//ファイルに書かれていたCPUアドレス
+    bank:Option<u16>,//ROMのラベルならPRGバンクの番号
+}
+
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct SymbolTable{
+    by_name:HashMap<String,Symbol>,
+    by_addr:BTreeMap<u16,String>,//バンクのないラベル。同じアドレスに複数の名前があれば最初のもの
+    by_bank:BTreeMap<(u16,u16),String>,//ROMのラベルを(バンク,バンク内のオフセット)で引く
+    rom_addrs:BTreeMap<u16,String>,//banksが分からないときはROMのラベルも書かれていたアドレスで引く
+    /// $8000~$BFFFと$C000~$FFFFに見えているPRGバンク。NoneならROMのラベルはバンクを気にせず使う
+    pub banks:Option<[u16;2]>,
+}
+
+impl SymbolTable{
+    pub fn new()->Self{
+        Self::default()
+    }
+
+    pub fn insert(&mut self,name:&str,addr:u16){
+        self.insert_in_bank(name,addr,None);
+    }
+
+    /// ROMのラベルはbankにPRGバンク(16KB単位)の番号を付ける
+    pub fn insert_in_bank(&mut self,name:&str,addr:u16,bank:Option<u16>){
+        self.by_name.insert(name.to_string(),Symbol{addr,bank});
+        match bank{
+            Some(bank)=>{
+                self.by_bank.entry((bank,addr&0x3FFF)).or_insert_with(||name.to_string());
+                self.rom_addrs.entry(addr).or_insert_with(||name.to_string());
+            }
+            None=>{
+                self.by_addr.entry(addr).or_insert_with(||name.to_string());
+            }
+        }
+    }
+
+    /// 別の表のラベルを足す。同じ名前なら後から足した方で上書きする
+    pub fn extend(&mut self,other:SymbolTable){
+        for (name,symbol) in other.by_name{
+            self.by_name.insert(name,symbol);
+        }
+        for (addr,name) in other.by_addr{
+            self.by_addr.entry(addr).or_insert(name);
+        }
+        for (key,name) in other.by_bank{
+            self.by_bank.entry(key).or_insert(name);
+        }
+        for (addr,name) in other.rom_addrs{
+            self.rom_addrs.entry(addr).or_insert(name);
+        }
+        if self.banks.is_none(){
+            self.banks=other.banks;
+        }
+    }
+
+    /// NROMのように固定でPRG-ROMを置く。16KBなら$C000~にも同じバンクが見える
+    pub fn map_nrom(&mut self,prg_rom_len:usize){
+        self.banks=Some(if prg_rom_len>PRG_BANK_SIZE{[0,1]}else{[0,0]});
+    }
+
+    /// 名前のアドレス。ROMのラベルは今見えているバンクの場所を返す
+    pub fn lookup(&self,name:&str)->Option<u16>{
+        let symbol=self.by_name.get(name)?;
+        match (symbol.bank,self.banks){
+            (Some(bank),Some(banks)) if symbol.addr>=PRG_ROM&&banks[slot(symbol.addr)]!=bank=>{
+                match banks.iter().position(|mapped|*mapped==bank){
+                    Some(slot)=>Some(PRG_ROM+(slot*PRG_BANK_SIZE) as u16+(symbol.addr&0x3FFF)),
+                    None=>Some(symbol.addr),//見えていなくても書かれていた場所で止められるようにする
+                }
+            }
+            _=>Some(symbol.addr),
+        }
+    }
+
+    pub fn name_at(&self,addr:u16)->Option<&str>{
+        if addr>=PRG_ROM{
+            let rom=match self.banks{
+                Some(banks)=>self.by_bank.get(&(banks[slot(addr)],addr&0x3FFF)),
+                None=>self.rom_addrs.get(&addr),
+            };
+            if let Some(name)=rom{
+                return Some(name);
+            }
+        }
+        self.by_addr.get(&addr).map(|name|name.as_str())
+    }
+
+    /// "$C000 <reset>"、名前がなければ"$C000"
+    pub fn label(&self,addr:u16)->String{
+        match self.name_at(addr){
+            Some(name)=>format!("${:04X} <{}>",addr,name),
+            None=>format!("${:04X}",addr),
+        }
+    }
+
+    pub fn len(&self)->usize{
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.by_name.is_empty()
+    }
+
+    /// 自前のアセンブラのラベル。定数はアドレスではないので入れない
+    pub fn from_assembly(assembly:&Assembly)->Self{
+        let mut table=Self::new();
+        let mut symbols:Vec<(&String,&u16)>=assembly.labels.iter().collect();
+        symbols.sort();//同じアドレスのときに選ばれる名前を毎回同じにする
+        for (name,addr) in symbols{
+            table.insert(name,*addr);
+        }
+        table
+    }
+
+    /// 拡張子で形式を選んで読む。それ以外は"name = $addr"の一覧として読む
+    pub fn load(path:&str)->Result<Self,Box<dyn Error>>{
+        let text=fs::read_to_string(path)?;
+        let lower=path.to_ascii_lowercase();
+        let table=if lower.ends_with(".s")||lower.ends_with(".asm"){
+            Self::from_assembly(&asm::assemble_program(&text)?)
+        }else if lower.ends_with(".dbg"){
+            Self::parse_ca65(&text)?
+        }else if lower.ends_with(".nl"){
+            Self::parse_fceux(&text,fceux_bank(path))?
+        }else if lower.ends_with(".mlb"){
+            Self::parse_mesen(&text)?
+        }else{
+            Self::parse(&text)?
+        };
+        Ok(table)
+    }
+
+    /// 1行に"name = $0600"か"name $0600"を並べたテキスト。;から後はコメント
+    pub fn parse(text:&str)->Result<Self,String>{
+        let mut table=Self::new();
+        for (i,line) in text.lines().enumerate(){
+            let line=line.split(';').next().unwrap_or("").trim();
+            if line.is_empty(){
+                continue;
+            }
+            let mut words=line.split(|c:char|c=='='||c.is_whitespace()).filter(|word|!word.is_empty());
+            let (name,value)=match (words.next(),words.next(),words.next()){
+                (Some(name),Some(value),None)=>(name,value),
+                _=>return Err(format!("line {}: expected \"name = $addr\"",i+1)),
+            };
+            let digits=value.trim_start_matches('$').trim_start_matches("0x");
+            let addr=u16::from_str_radix(digits,16).map_err(|_|format!("line {}: invalid address: {}",i+1,value))?;
+            table.insert(name,addr);
+        }
+        Ok(table)
+    }
+
+    /// ld65 --dbgfileの出力。segとsymの行だけを見て、ラベル(type=lab)を取り出す。
+    /// .nesに出力されたセグメントのラベルは、ファイル内の位置(ooffs)からPRGバンクを求める
+    pub fn parse_ca65(text:&str)->Result<Self,String>{
+        //segのid→(開始アドレス,PRG-ROM内の位置)
+        let mut segments:HashMap<u64,(u64,Option<u64>)>=HashMap::new();
+        let mut labels=vec![];
+        for (i,line) in text.lines().enumerate(){
+            let (kind,rest)=match line.split_once(char::is_whitespace){
+                Some(split)=>split,
+                None=>continue,
+            };
+            if kind!="seg"&&kind!="sym"{
+                continue;
+            }
+            let fields=dbg_fields(rest).map_err(|e|format!("line {}: {}",i+1,e))?;
+            let number=|key:&str|fields.get(key).and_then(|value|parse_dbg_number(value));
+            let id=number("id").ok_or_else(||format!("line {}: {} without id",i+1,kind))?;
+            if kind=="seg"{
+                let start=number("start").unwrap_or(0);
+                let nes=fields.get("oname").is_some_and(|name|name.to_ascii_lowercase().ends_with(".nes"));
+                //iNESのヘッダ(16byte)の後ろがPRG-ROM
+                let prg=number("ooffs").filter(|_|nes).map(|ooffs|ooffs.saturating_sub(16));
+                segments.insert(id,(start,prg));
+            }else if fields.get("type").map(|t|t.as_str())==Some("lab"){
+                let (Some(name),Some(value))=(fields.get("name"),number("val")) else{
+                    continue;
+                };
+                labels.push((name.clone(),value,number("seg")));
+            }
+        }
+
+        let mut table=Self::new();
+        for (name,value,seg) in labels{
+            let addr=u16::try_from(value).map_err(|_|format!("{}: address out of range: {:#X}",name,value))?;
+            let bank=match seg.and_then(|seg|segments.get(&seg)){
+                //セグメントの開始より前を指すラベル(壊れた.dbg)にはバンクを付けない
+                Some((start,Some(prg))) if addr>=PRG_ROM=>prg
+                    .checked_add(value)
+                    .and_then(|offset|offset.checked_sub(*start))
+                    .map(|offset|(offset/PRG_BANK_SIZE as u64) as u16),
+                _=>None,
+            };
+            table.insert_in_bank(&name,addr,bank);
+        }
+        Ok(table)
+    }
+
+    /// FCEUXの"$C000#Reset#comment"の並び。ROMのバンクごとのファイル(game.nes.0.nl)ならbankを付ける
+    pub fn parse_fceux(text:&str,bank:Option<u16>)->Result<Self,String>{
+        let mut table=Self::new();
+        for (i,line) in text.lines().enumerate(){
+            //\で始まる行は前の行のコメントの続き
+            let Some(line)=line.trim().strip_prefix('$') else{
+                continue;
+            };
+            let mut fields=line.split('#');
+            let (addr,name)=match (fields.next(),fields.next()){
+                (Some(addr),Some(name))=>(addr,name.trim()),
+                _=>return Err(format!("line {}: expected \"$addr#name#comment\"",i+1)),
+            };
+            //"$0300/10"のように配列の大きさが付いていることがある
+            let addr=addr.split('/').next().unwrap_or(addr);
+            let addr=u16::from_str_radix(addr,16).map_err(|_|format!("line {}: invalid address: {}",i+1,addr))?;
+            if name.is_empty(){
+                continue;
+            }
+            table.insert_in_bank(name,addr,bank.filter(|_|addr>=PRG_ROM));
+        }
+        Ok(table)
+    }
+
+    /// Mesenの"P:0123:label:comment"の並び。PはPRG-ROM内の位置、R/S/W/GはRAMやレジスタ
+    pub fn parse_mesen(text:&str)->Result<Self,String>{
+        let mut table=Self::new();
+        for (i,line) in text.lines().enumerate(){
+            let line=line.trim();
+            if line.is_empty(){
+                continue;
+            }
+            let mut fields=line.splitn(4,':');
+            let (kind,offset,name)=match (fields.next(),fields.next(),fields.next()){
+                (Some(kind),Some(offset),Some(name))=>(kind,offset,name.trim()),
+                _=>return Err(format!("line {}: expected \"type:addr:label\"",i+1)),
+            };
+            //範囲("0010-0011")は先頭だけ使う
+            let offset=offset.split('-').next().unwrap_or(offset);
+            let offset=usize::from_str_radix(offset,16).map_err(|_|format!("line {}: invalid address: {}",i+1,offset))?;
+            if name.is_empty(){
+                continue;//コメントだけの行
+            }
+            match kind{
+                //PRG-ROMのアドレスはマッパー次第なので、32KBごとに$8000から並べた場所にしておく
+                "P"|"NesPrgRom"=>{
+                    let addr=PRG_ROM|(offset&0x7FFF) as u16;
+                    table.insert_in_bank(name,addr,Some((offset/PRG_BANK_SIZE) as u16));
+                }
+                "R"|"NesInternalRam"|"G"|"NesMemory"|"NesRegister"=>table.insert(name,offset as u16),
+                "S"|"W"|"NesSaveRam"|"NesWorkRam"=>table.insert(name,PRG_RAM.wrapping_add(offset as u16)),
+                _=>{}//CHRなどCPUからは見えないもの
+            }
+        }
+        Ok(table)
+    }
+}
+
+fn slot(addr:u16)->usize{
+    ((addr-PRG_ROM) as usize)/PRG_BANK_SIZE
+}
+
+//"game.nes.1.nl"ならバンク1、"game.nes.ram.nl"ならRAMなのでNone
+fn fceux_bank(path:&str)->Option<u16>{
+    let stem=Path::new(path).file_stem()?.to_str()?;
+    let (_,bank)=stem.rsplit_once('.')?;
+    u16::from_str_radix(bank,16).ok()
+}
+
+//"id=0,name=\"reset\",val=0x8000"を分ける。""の中のカンマは区切りではない
+fn dbg_fields(text:&str)->Result<HashMap<String,String>,String>{
+    let mut fields=HashMap::new();
+    let mut rest=text.trim();
+    while !rest.is_empty(){
+        let (key,after)=rest.split_once('=').ok_or_else(||format!("expected key=value: {}",rest))?;
+        let (value,after)=match after.strip_prefix('"'){
+            Some(quoted)=>{
+                let end=quoted.find('"').ok_or("unterminated string")?;
+                (&quoted[..end],&quoted[end+1..])
+            }
+            None=>after.split_once(',').unwrap_or((after,"")),
+        };
+        fields.insert(key.trim().to_string(),value.to_string());
+        rest=after.trim_start_matches(',').trim();
+    }
+    Ok(fields)
+}
+
+fn parse_dbg_number(text:&str)->Option<u64>{
+    match text.strip_prefix("0x"){
+        Some(digits)=>u64::from_str_radix(digits,16).ok(),
+        None=>text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::asm::assemble_program;
+
+    #[test]
+    fn test_parse_and_lookup(){
+        let table=SymbolTable::parse("reset = $8000 ; entry\nnmi $8010\n\nirq=0x8010\n").unwrap();
+        assert_eq!(table.len(),3);
+        assert_eq!(table.lookup("irq"),Some(0x8010));
+        assert_eq!(table.name_at(0x8000),Some("reset"));
+        assert_eq!(table.name_at(0x8010),Some("nmi"));
+        assert_eq!(table.label(0x8000),"$8000 <reset>");
+        assert_eq!(table.label(0x8001),"$8001");
+        assert!(SymbolTable::parse("reset = $80000").is_err());
+        assert!(SymbolTable::parse("reset").is_err());
+    }
+
+    #[test]
+    fn test_from_assembly(){
+        let assembly=assemble_program("start:\n  jmp start\n").unwrap();
+        let table=SymbolTable::from_assembly(&assembly);
+        assert_eq!(table.lookup("start"),Some(0x0600));
+    }
+
+    #[test]
+    fn test_from_assembly_skips_constants(){
+        //snakeでは向きの定数movingRight(2)と変数snakeDirection($02)が同じ値になる
+        let assembly=assemble_program(include_str!("snake.asm")).unwrap();
+        let table=SymbolTable::from_assembly(&assembly);
+        assert_eq!(table.name_at(0x0002),Some("snakeDirection"));
+        assert_eq!(table.lookup("movingRight"),None);
+        assert_eq!(table.name_at(0x00FE),Some("sysRandom"));
+    }
+
+    #[test]
+    fn test_parse_ca65_dbg(){
+        let text="version\tmajor=2,minor=0
+seg\tid=0,name=\"ZEROPAGE\",start=0x000000,size=0x0002,addrsize=zeropage,type=rw
+seg\tid=1,name=\"CODE\",start=0x008000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+seg\tid=2,name=\"FIXED\",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400
+sym\tid=0,name=\"reset\",addrsize=absolute,size=1,scope=0,def=3,ref=5,val=0x8000,seg=1,type=lab
+sym\tid=1,name=\"nmi\",addrsize=absolute,scope=0,def=4,val=0xC010,seg=2,type=lab
+sym\tid=2,name=\"frame\",addrsize=zeropage,scope=0,def=6,val=0x0,seg=0,type=lab
+sym\tid=3,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=1,val=0x2000,type=equ
+";
+        let table=SymbolTable::parse_ca65(text).unwrap();
+        assert_eq!(table.len(),3);
+        assert_eq!(table.lookup("reset"),Some(0x8000));
+        assert_eq!(table.name_at(0x0000),Some("frame"));
+        assert_eq!(table.name_at(0x2000),None);
+        assert_eq!(table.by_name["nmi"].bank,Some(1));
+        assert_eq!(table.by_name["frame"].bank,None);
+        assert!(SymbolTable::parse_ca65("sym\tid=0,name=\"x").is_err());
+
+        //セグメントの開始より前のラベル
+        let text="seg\tid=0,name=\"CODE\",start=0x00C000,oname=\"game.nes\",ooffs=16
+sym\tid=0,name=\"early\",val=0x8000,seg=0,type=lab
+";
+        let table=SymbolTable::parse_ca65(text).unwrap();
+        assert_eq!(table.lookup("early"),Some(0x8000));
+        assert_eq!(table.by_name["early"].bank,None);
+    }
+
+    #[test]
+    fn test_parse_fceux_nl(){
+        let text="$C000#Reset#entry point\n\\continued comment\n$C010/2#table#\n$C020##comment only\n";
+        let table=SymbolTable::parse_fceux(text,Some(1)).unwrap();
+        assert_eq!(table.len(),2);
+        assert_eq!(table.lookup("table"),Some(0xC010));
+        assert_eq!(table.by_name["Reset"].bank,Some(1));
+        let ram=SymbolTable::parse_fceux("$0300#buffer#\n",None).unwrap();
+        assert_eq!(ram.name_at(0x0300),Some("buffer"));
+        assert!(SymbolTable::parse_fceux("$ZZZZ#bad#",None).is_err());
+        assert_eq!(fceux_bank("roms/game.nes.A.nl"),Some(10));
+        assert_eq!(fceux_bank("roms/game.nes.ram.nl"),None);
+    }
+
+    #[test]
+    fn test_parse_mesen_mlb(){
+        let text="P:0000:reset:entry\nP:4010:nmi\nR:0010-0011:pointer\nW:0000:save_data\nG:2000:PPUCTRL\nP:0020::comment\nNesInternalRam:0300:buffer\n";
+        let table=SymbolTable::parse_mesen(text).unwrap();
+        assert_eq!(table.len(),6);
+        assert_eq!(table.lookup("reset"),Some(0x8000));
+        assert_eq!(table.lookup("nmi"),Some(0xC010));
+        assert_eq!(table.name_at(0x0010),Some("pointer"));
+        assert_eq!(table.name_at(0x6000),Some("save_data"));
+        assert_eq!(table.name_at(0x2000),Some("PPUCTRL"));
+        assert_eq!(table.name_at(0x0300),Some("buffer"));
+        assert!(SymbolTable::parse_mesen("P").is_err());
+    }
+
+    #[test]
+    fn test_banks_select_rom_labels(){
+        let mut table=SymbolTable::new();
+        table.insert_in_bank("bank0",0x8000,Some(0));
+        table.insert_in_bank("bank1",0xC000,Some(1));
+        table.insert_in_bank("bank2",0x8000,Some(2));
+        table.insert("ram",0x0200);
+        //バンクが分からなければ書かれていたアドレスで引く
+        assert_eq!(table.name_at(0x8000),Some("bank0"));
+
+        table.banks=Some([2,1]);
+        assert_eq!(table.name_at(0x8000),Some("bank2"));
+        assert_eq!(table.name_at(0xC000),Some("bank1"));
+        assert_eq!(table.name_at(0x0200),Some("ram"));
+
+        //16KBのNROMなら$C000~にも同じバンクが見える
+        table.map_nrom(0x4000);
+        assert_eq!(table.name_at(0xC000),Some("bank0"));
+        assert_eq!(table.lookup("bank1"),Some(0xC000));
+        assert_eq!(table.lookup("bank0"),Some(0x8000));
+
+        let mut merged=SymbolTable::parse("ram = $0200\nzp = $10").unwrap();
+        merged.extend(table);
+        assert_eq!(merged.len(),5);
+        assert_eq!(merged.name_at(0xC000),Some("bank0"));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::cpu::{AddressingMode, Mem, CPU};
+use crate::disasm;
+use crate::symbols::SymbolTable;
+use std::io::{self, Write};
+
+/// 次に実行する命令をnestest.logと同じ形式の1行にする
+///
+/// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7`
+pub fn trace<M:Mem>(cpu:&mut CPU<M>)->String{
+    format_trace(cpu,None)
+}
+
+/// traceと同じ。オペランドのアドレスをラベルにする(`JSR init_snake`)
+pub fn trace_with_symbols<M:Mem>(cpu:&mut CPU<M>,symbols:&SymbolTable)->String{
+    format_trace(cpu,Some(symbols))
+}
+
+fn format_trace<M:Mem>(cpu:&mut CPU<M>,symbols:Option<&SymbolTable>)->String{
+    let begin=cpu.program_counter;
+    let variant=cpu.variant;
+    let instruction=disasm::decode_at_for(variant,cpu,begin);
+
+    //実効アドレスとそこにある値(命令を実行する前の値)
+    let resolved=match instruction.mode{
+        Some(mode)=>match mode{
+            AddressingMode::Immediate|AddressingMode::Accumulator|AddressingMode::NoneAddressing
+            |AddressingMode::Relative=>None,
+            _=>cpu.get_absolute_address(&mode,begin.wrapping_add(1)).ok().map(|(addr,_)|(mode,addr)),
+        },
+        None=>None,
+    };
+
+    let suffix=match resolved{
+        Some((mode,mem_addr))=>{
+            let stored_value=cpu.peek(mem_addr);
+            match mode{
+                AddressingMode::ZeroPage=>format!(" = {:02x}",stored_value),
+                AddressingMode::ZeroPage_X|AddressingMode::ZeroPage_Y=>format!(" @ {:02x} = {:02x}",mem_addr,stored_value),
+                AddressingMode::Indirect_X=>format!(
+                    " @ {:02x} = {:04x} = {:02x}",
+                    instruction.bytes[1].wrapping_add(cpu.register_x),
+                    mem_addr,
+                    stored_value
+                ),
+                AddressingMode::Indirect_Y=>format!(
+                    " = {:04x} @ {:04x} = {:02x}",
+                    mem_addr.wrapping_sub(cpu.register_y as u16),
+                    mem_addr,
+                    stored_value
+                ),
+                AddressingMode::Indirect=>format!(" = {:04x}",mem_addr),
+                //JMP,JSRは飛び先だけを表示する
+                AddressingMode::Absolute if instruction.target().is_some()=>String::new(),
+                AddressingMode::Absolute=>format!(" = {:02x}",stored_value),
+                AddressingMode::Absolute_X|AddressingMode::Absolute_Y=>format!(" @ {:04x} = {:02x}",mem_addr,stored_value),
+                _=>String::new(),
+            }
+        }
+        None=>String::new(),
+    };
+
+    let mnemonic=if instruction.unofficial{
+        format!("*{}",instruction.mnemonic)
+    }else{
+        instruction.mnemonic.to_string()
+    };
+    //ラベルは大文字にしない
+    let operand=match symbols{
+        Some(symbols)=>instruction.operand_with_symbols(symbols),
+        None=>instruction.operand.clone(),
+    };
+    let asm_str=format!("{:04X}  {:8} {: >4} {}{}",begin,instruction.hex(),mnemonic,operand,suffix.to_ascii_uppercase())
+        .trim_end()
+        .to_string();
+
+    format!("{:47} {}",asm_str,registers(cpu))
+}
+
+fn registers<M:Mem>(cpu:&CPU<M>)->String{
+    let r=cpu.registers();
+    format!(
+        "A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} CYC:{}",
+        r.a,r.x,r.y,r.p.bits(),r.sp,r.cycles
+    ).to_ascii_uppercase()
+}
+
+/// traceの1行をoutに書き出す。run_with_callbackと組み合わせて使う
+pub fn write_trace<M:Mem,W:Write>(cpu:&mut CPU<M>,out:&mut W)->io::Result<()>{
+    writeln!(out,"{}",trace(cpu))
+}
+
+/// write_traceと同じ。trace_with_symbolsの形式で書く
+pub fn write_trace_with_symbols<M:Mem,W:Write>(cpu:&mut CPU<M>,symbols:&SymbolTable,out:&mut W)->io::Result<()>{
+    writeln!(out,"{}",trace_with_symbols(cpu,symbols))
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::bus::RecordingBus;
+
+    #[test]
+    fn test_format_trace(){
+        let mut cpu=CPU::new();
+        cpu.mem_write(100,0xa2);
+        cpu.mem_write(101,0x01);
+        cpu.mem_write(102,0xca);
+        cpu.mem_write(103,0x88);
+        cpu.mem_write(104,0x00);
+        cpu.program_counter=0x64;
+        cpu.register_a=1;
+        cpu.register_x=2;
+        cpu.register_y=3;
+        let mut result:Vec<String>=vec![];
+        cpu.run_with_callback(|cpu|{
+            result.push(trace(cpu));
+        }).unwrap();
+        assert_eq!(
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:20 SP:FD CYC:0",
+            result[0]
+        );
+        assert_eq!(
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:20 SP:FD CYC:2",
+            result[1]
+        );
+        assert_eq!(
+            "0067  88        DEY                             A:01 X:00 Y:03 P:22 SP:FD CYC:4",
+            result[2]
+        );
+    }
+
+    #[test]
+    fn test_format_mem_access(){
+        let mut cpu=CPU::new();
+        //ORA ($33),Y
+        cpu.mem_write(100,0x11);
+        cpu.mem_write(101,0x33);
+
+        //data
+        cpu.mem_write(0x33,0x00);
+        cpu.mem_write(0x34,0x04);
+
+        //target cell
+        cpu.mem_write(0x400,0xAA);
+
+        cpu.program_counter=0x64;
+        cpu.register_y=0;
+        let mut result:Vec<String>=vec![];
+        cpu.run_with_callback(|cpu|{
+            result.push(trace(cpu));
+        }).unwrap();
+        assert_eq!(
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:20 SP:FD CYC:0",
+            result[0]
+        );
+    }
+
+    #[test]
+    fn test_trace_does_not_touch_bus(){
+        let mut cpu=CPU::with_bus(RecordingBus::new());
+        //ORA ($33),Y
+        cpu.bus.poke(0x0600,0x11);
+        cpu.bus.poke(0x0601,0x33);
+        cpu.bus.poke(0x0034,0x04);
+        cpu.program_counter=0x0600;
+        assert_eq!(&trace(&mut cpu)[..48],"0600  11 33     ORA ($33),Y = 0400 @ 0400 = 00  ");
+        assert!(cpu.bus.accesses.is_empty());
+    }
+
+    #[test]
+    fn test_format_jumps_and_unofficial(){
+        let mut cpu=CPU::new();
+        //JMP ($0200)
+        cpu.mem_write(0x0600,0x6c);
+        cpu.mem_write_u16(0x0601,0x0200);
+        cpu.mem_write_u16(0x0200,0x0700);
+        //BNE -2
+        cpu.mem_write(0x0700,0xd0);
+        cpu.mem_write(0x0701,0xfe);
+        //*LAX $10,Y
+        cpu.mem_write(0x0702,0xb7);
+        cpu.mem_write(0x0703,0x10);
+        //LSR A
+        cpu.mem_write(0x0704,0x4a);
+
+        cpu.program_counter=0x0600;
+        assert_eq!(&trace(&mut cpu)[..48],"0600  6C 00 02  JMP ($0200) = 0700              ");
+        cpu.program_counter=0x0700;
+        assert_eq!(&trace(&mut cpu)[..48],"0700  D0 FE     BNE $0700                       ");
+        cpu.program_counter=0x0702;
+        assert_eq!(&trace(&mut cpu)[..48],"0702  B7 10    *LAX $10,Y @ 10 = 00             ");
+        cpu.program_counter=0x0704;
+        assert_eq!(&trace(&mut cpu)[..48],"0704  4A        LSR A                           ");
+    }
+
+    #[test]
+    fn test_trace_with_symbols(){
+        let mut cpu=CPU::new();
+        //JSR $0606; STA $0200,X; LDA #$06; DEX
+        cpu.load(vec![0x20,0x06,0x06,0x9d,0x00,0x02,0xa9,0x06,0xca]).unwrap();
+        let symbols=SymbolTable::parse("initSnake = $0606\nscreen = $0200").unwrap();
+        cpu.program_counter=0x0600;
+        assert_eq!(&trace_with_symbols(&mut cpu,&symbols)[..48],"0600  20 06 06  JSR initSnake                   ");
+        cpu.program_counter=0x0603;
+        assert_eq!(&trace_with_symbols(&mut cpu,&symbols)[..48],"0603  9D 00 02  STA screen,X @ 0200 = 00        ");
+        cpu.program_counter=0x0606;
+        assert_eq!(trace_with_symbols(&mut cpu,&symbols),trace(&mut cpu));
+        cpu.program_counter=0x0608;
+        assert_eq!(trace_with_symbols(&mut cpu,&symbols),trace(&mut cpu));
+    }
+
+    #[test]
+    fn test_write_trace(){
+        let mut cpu=CPU::new();
+        cpu.load(vec![0xa9, 0x05, 0x00]).unwrap();
+        cpu.power_on();
+        let mut out:Vec<u8>=vec![];
+        cpu.run_with_callback(|cpu|{
+            write_trace(cpu,&mut out).unwrap();
+        }).unwrap();
+        let text=String::from_utf8(out).unwrap();
+        let lines:Vec<&str>=text.lines().collect();
+        assert_eq!(lines.len(),2);
+        assert!(lines[0].starts_with("0600  A9 05     LDA #$05"));
+        assert!(lines[1].starts_with("0602  00        BRK"));
+    }
+}